@@ -0,0 +1,232 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::collections::HashMap;
+
+use tag::action::ActionContext;
+use tag::agent::{AgentManager, Id};
+use tag::config::{AgentType, Config, OutsideRule, Schedule, Space, TagBackRules, Topology};
+use tag::engine::Engine;
+use tag::grid::{body_size, Grid, Position, SubgridSearch};
+use tag::state::State;
+
+/// Grid sides and agent counts the hot paths are measured at
+const SIZES: &[(usize, usize)] = &[(250, 500), (1000, 2000), (1000, 8000)];
+/// Body side and tag reach of every agent
+const BODY_SIDE: usize = 3;
+const TAG_REACH: usize = 11;
+/// Side lengths of the subgrids searched around agents, which are those
+/// the actions search at every step
+const SUBGRID_SIDES: &[usize] = &[BODY_SIDE, TAG_REACH];
+/// Number of agents around which subgrids are searched in one iteration
+const QUERIES: usize = 256;
+
+/// Config of a flat, empty grid of small agents. Everything which affects
+/// the measurements is set here, except for the game mode with its rules and
+/// teams, which are taken from lib.rs so that every mode can be measured
+fn config(grid_side: usize, num_agents: usize, collision_detection: bool) -> Config {
+    Config {
+        grid_side,
+        levels: 1,
+        space: Space::Discrete,
+        topology: Topology::VonNeumann,
+        schedule: Schedule::Synchronous,
+        num_agents,
+        num_agents_it: 2,
+        collision_detection,
+        validate: false,
+        agent_types: vec![AgentType {
+            name: "bench",
+            share: 1.0,
+            body_side: (BODY_SIDE, BODY_SIDE),
+            tag_reach: (TAG_REACH, TAG_REACH),
+            speed: (1, 3),
+            vision_radius: (TAG_REACH / 2, TAG_REACH / 2),
+            impassable: &[],
+        }],
+        max_stamina: 100.0,
+        step_stamina_cost: 0.0,
+        rest_stamina_gain: 0.0,
+        sprint_stamina_cost: 10.0,
+        sprint_duration: 1,
+        it_speed_bonus: 0,
+        tag_cooldown: 0,
+        tag_back_rules: TagBackRules {
+            no_tag_back_steps: usize::MAX,
+            retag_window: 0,
+            immunity_steps: 0,
+            history_length: 1,
+        },
+        seed: Some(0),
+        num_rounds: 1,
+        // Rounds never end on their own, however many iterations are run
+        num_steps: usize::MAX,
+        spawn_points: vec![],
+        safe_zones: vec![],
+        safe_zone_stay_limit: None,
+        terrain: vec![],
+        obstacles: vec![],
+        doors: vec![],
+        boundary: None,
+        outside_rule: OutsideRule::Teleport,
+        infection_curve_path: None,
+        ..Config::default()
+    }
+}
+
+fn setup(config: &Config) -> (ActionContext, Grid, AgentManager) {
+    let ac = ActionContext::new(config);
+    let mut grid = Grid::new(config);
+    let am = AgentManager::new(config, &ac, &mut grid);
+    // Agents which do not fit are left out, which would make the
+    // measurements of different sizes incomparable
+    assert_eq!(
+        am.get_ids().len(),
+        config.num_agents,
+        "population not reached"
+    );
+    (ac, grid, am)
+}
+
+/// Agents and their positions, around which subgrids are searched
+fn queries(am: &AgentManager) -> Vec<(Id, Position)> {
+    am.get_ids_in_state(State::Runner)
+        .into_iter()
+        .take(QUERIES)
+        .map(|id| (id, am.get_position(id)))
+        .collect()
+}
+
+fn grid_queries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("is_subgrid_free");
+    for &(grid_side, num_agents) in SIZES {
+        let (_, grid, am) = setup(&config(grid_side, num_agents, true));
+        let queries = queries(&am);
+        for &side in SUBGRID_SIDES {
+            let parameter = format!("{}x{}/{}/{}", grid_side, grid_side, num_agents, side);
+            group.bench_with_input(BenchmarkId::from_parameter(parameter), &side, |b, &side| {
+                b.iter(|| {
+                    for &(id, position) in &queries {
+                        black_box(grid.is_subgrid_free(position, body_size(side), vec![id], None));
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("get_subgrid_occupiers");
+    for &(grid_side, num_agents) in SIZES {
+        let (_, grid, am) = setup(&config(grid_side, num_agents, true));
+        let queries = queries(&am);
+        let is_it = |other: Id| am.get_state(other) == State::It;
+        for &side in SUBGRID_SIDES {
+            let parameter = format!("{}x{}/{}/{}", grid_side, grid_side, num_agents, side);
+            group.bench_with_input(BenchmarkId::from_parameter(parameter), &side, |b, &side| {
+                b.iter(|| {
+                    for &(id, position) in &queries {
+                        black_box(grid.get_subgrid_occupiers(
+                            position,
+                            body_size(side),
+                            vec![id],
+                            Some(&is_it),
+                        ));
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("subgrid_search");
+    for &(grid_side, num_agents) in SIZES {
+        let (_, grid, am) = setup(&config(grid_side, num_agents, true));
+        let queries = queries(&am);
+        for &side in SUBGRID_SIDES {
+            let parameter = format!("{}x{}/{}/{}", grid_side, grid_side, num_agents, side);
+            group.bench_with_input(BenchmarkId::from_parameter(parameter), &side, |b, &side| {
+                b.iter(|| {
+                    for &(id, position) in &queries {
+                        let size = body_size(side);
+                        let center = grid.get_subgrid_center(size);
+                        let search = SubgridSearch::new(position, center, size, vec![id], &grid);
+                        black_box(search.count());
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Looks up every agent by its Id, which goes through its slot, next to
+/// the same lookups in a map from Ids to agent indices, as they were made
+/// before Ids were slot handles
+fn id_lookups(c: &mut Criterion) {
+    let mut group = c.benchmark_group("id_lookup");
+    for &(grid_side, num_agents) in SIZES {
+        let (_, _, am) = setup(&config(grid_side, num_agents, true));
+        let ids: Vec<Id> = am.get_ids().to_vec();
+        let parameter = format!("{}x{}/{}/slots", grid_side, grid_side, num_agents);
+        group.bench_function(BenchmarkId::from_parameter(parameter), |b| {
+            b.iter(|| {
+                for &id in &ids {
+                    black_box(am.get_position(id));
+                }
+            })
+        });
+        let id_map: HashMap<Id, usize> = ids.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+        let parameter = format!("{}x{}/{}/hash_map", grid_side, grid_side, num_agents);
+        group.bench_function(BenchmarkId::from_parameter(parameter), |b| {
+            b.iter(|| {
+                for id in &ids {
+                    black_box(id_map[id]);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn steps(c: &mut Criterion) {
+    let mut group = c.benchmark_group("perform_actions");
+    group.sample_size(20);
+    for &(grid_side, num_agents) in SIZES {
+        for &collision_detection in &[true, false] {
+            let config = config(grid_side, num_agents, collision_detection);
+            let (ac, mut grid, mut am) = setup(&config);
+            let parameter = format!(
+                "{}x{}/{}/collisions={}",
+                grid_side, grid_side, num_agents, collision_detection
+            );
+            group.bench_function(BenchmarkId::from_parameter(parameter), |b| {
+                b.iter(|| {
+                    am.perform_actions(&grid, &ac);
+                    // Keeps the grid in sync, so that later iterations see
+                    // the agents where they are
+                    grid.update(am.flush_log());
+                })
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("engine_step");
+    group.sample_size(20);
+    for &(grid_side, num_agents) in SIZES {
+        for &collision_detection in &[true, false] {
+            let config = config(grid_side, num_agents, collision_detection);
+            let (ac, grid, am) = setup(&config);
+            let mut engine = Engine::new(&config, grid, ac, am, false);
+            let parameter = format!(
+                "{}x{}/{}/collisions={}",
+                grid_side, grid_side, num_agents, collision_detection
+            );
+            group.bench_function(BenchmarkId::from_parameter(parameter), |b| {
+                b.iter(|| engine.step())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, grid_queries, id_lookups, steps);
+criterion_main!(benches);
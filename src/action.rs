@@ -1,16 +1,72 @@
-use crate::{COLLSION_DETECTION, GRID_SIDE, STEP_SG_SIDE, TAG_SG_SIDE};
-
 use crate::agent::{AgentManager, Id};
-use crate::grid::{Grid, Position};
+use crate::config::{Config, Space, TerrainEffects};
+use crate::grid::{body_size, cube_size, Grid, Point, Position};
+use crate::state::Trigger;
 
 use rand::seq::SliceRandom;
+use rand::Rng;
+use std::cmp;
 
-pub type Precondition = fn(Id, &AgentManager, &Grid) -> bool;
-pub type Effect = fn(Id, &mut AgentManager, &Grid);
+/// Preconditions and effects are boxed closures, so that they can capture
+/// their own state (e.g. step size or subgrid size taken from a `Config`).
+/// They need to be `Send + Sync` as preconditions are evaluated in parallel
+pub type Precondition = Box<dyn Fn(Id, &AgentManager, &Grid) -> bool + Send + Sync>;
+pub type Effect = Box<dyn Fn(Id, &mut AgentManager, &Grid) + Send + Sync>;
+/// Destination of an action which only moves the agent, or None if it stays
+/// in place. Unlike effects, movements only read the agent manager and only
+/// change the moving agent, so movements away from any effect are committed
+/// in parallel. In discrete space destinations are cells, given as
+/// `Point::from(position)`
+pub type Movement = Box<dyn Fn(Id, &AgentManager, &Grid) -> Option<Point> + Send + Sync>;
+/// Side of the square, centred on the agent, outside of which an effect
+/// neither reads nor changes other agents
+pub type Halo = Box<dyn Fn(Id, &AgentManager) -> usize + Send + Sync>;
 
 pub struct Action {
     pub precond: Precondition,
     pub effect: Effect,
+    /// If set, the action is a movement which replaces the effect
+    pub movement: Option<Movement>,
+    /// Number of steps after use during which the action can not be chosen again
+    pub cooldown: usize,
+    /// Number of steps the action takes, its effect is realised on the last
+    /// one. A duration of 0 is taken as 1
+    pub duration: usize,
+    /// Stamina needed and used up by the action, negative values restore stamina
+    pub stamina_cost: f32,
+    /// If set, a movement only gets the agent onto a cell with the move
+    /// probability of its terrain, which is rolled when the movement is
+    /// performed. If the roll fails the agent stays in place, and the action
+    /// is still spent
+    pub terrain_effects: Option<TerrainEffects>,
+    /// If set, the effect only reaches agents within the halo, so agents
+    /// outside of it can act at the same time. Otherwise the effect may reach
+    /// any agent
+    pub halo: Option<Halo>,
+}
+
+impl Action {
+    /// A free action which takes one step and has no cooldown
+    pub fn new(precond: Precondition, effect: Effect) -> Action {
+        Action {
+            precond,
+            effect,
+            movement: None,
+            cooldown: 0,
+            duration: 1,
+            stamina_cost: 0.0,
+            terrain_effects: None,
+            halo: None,
+        }
+    }
+
+    /// A free movement which takes one step and has no cooldown
+    pub fn movement(precond: Precondition, movement: Movement) -> Action {
+        Action {
+            movement: Some(movement),
+            ..Action::new(precond, Box::new(|_, _, _| {}))
+        }
+    }
 }
 
 pub struct ActionContext {
@@ -28,207 +84,563 @@ impl ActionContext {
         &self.mean_preferences
     }
 
-    pub fn maybe_get_allowed_effect(
+    pub fn get_duration(&self, action_ix: usize) -> usize {
+        self.actions[action_ix].duration
+    }
+
+    /// Whether the action is off cooldown, the agent has enough stamina
+    /// for it and its precondition holds. Agents outside the boundary can
+    /// always move towards it, even without stamina
+    pub fn is_allowed(&self, action_ix: usize, id: Id, am: &AgentManager, grid: &Grid) -> bool {
+        let action: &Action = &self.actions[action_ix];
+        am.get_cooldown(id, action_ix) == 0
+            && (action.stamina_cost <= 0.0
+                || am.get_stamina(id) >= action.stamina_cost
+                || self.is_way_back(action_ix, id, am, grid))
+            && (action.precond)(id, am, grid)
+    }
+
+    /// Whether the action moves the agent closer to the boundary from
+    /// outside of it
+    fn is_way_back(&self, action_ix: usize, id: Id, am: &AgentManager, grid: &Grid) -> bool {
+        self.get_destination(action_ix, id, am, grid)
+            .is_some_and(|destination| grid.is_way_back(am.get_position(id), destination.cell()))
+    }
+
+    /// Returns the index of the first allowed action in `actions_ordering`
+    pub fn maybe_get_allowed_action(
         &self,
         actions_ordering: &[usize],
         id: Id,
         am: &AgentManager,
         grid: &Grid,
-    ) -> Option<Effect> {
-        for j in 0..self.actions.len() {
-            let action: &Action = &self.actions[actions_ordering[j]];
-            let action_allowed = (action.precond)(id, am, grid);
-            if action_allowed {
-                return Some(action.effect);
-            }
+    ) -> Option<usize> {
+        actions_ordering
+            .iter()
+            .find(|&&ix| self.is_allowed(ix, id, am, grid))
+            .copied()
+    }
+
+    pub fn is_movement(&self, action_ix: usize) -> bool {
+        self.actions[action_ix].movement.is_some()
+    }
+
+    /// Where a movement takes the agent, None if it stays in place or the
+    /// action is not a movement
+    pub fn get_destination(
+        &self,
+        action_ix: usize,
+        id: Id,
+        am: &AgentManager,
+        grid: &Grid,
+    ) -> Option<Point> {
+        self.actions[action_ix]
+            .movement
+            .as_ref()
+            .and_then(|movement| movement(id, am, grid))
+    }
+
+    /// Where a movement takes the agent when it is performed, which is its
+    /// destination unless the agent fails to get onto the terrain there. The
+    /// roll uses the agent's own random number generator, so it is the same
+    /// whichever order agents act in
+    pub fn roll_destination(
+        &self,
+        action_ix: usize,
+        id: Id,
+        am: &AgentManager,
+        grid: &Grid,
+    ) -> Option<Point> {
+        let destination: Point = self.get_destination(action_ix, id, am, grid)?;
+        let terrain_effects = match self.actions[action_ix].terrain_effects {
+            Some(terrain_effects) => terrain_effects,
+            None => return Some(destination),
+        };
+        let move_probability: f32 = terrain_effects
+            .of(grid.terrain_at(destination.cell()))
+            .move_probability;
+        if move_probability >= 1.0 || am.agent_rng(id).gen::<f32>() < move_probability {
+            Some(destination)
+        } else {
+            None
         }
-        None
     }
 
-    pub fn new() -> ActionContext {
-        let left_step_precond: Precondition = |id, am, grid| {
-            let position = am.get_position(id);
-            if position.x == 0 {
-                return false;
-            }
-            if COLLSION_DETECTION {
-                grid.is_subgrid_free(
-                    Position {
-                        x: position.x - 1,
-                        y: position.y,
-                    },
-                    STEP_SG_SIDE,
-                    STEP_SG_SIDE,
-                    vec![id],
-                    None,
-                )
-            } else {
-                true
+    /// Side of the square around the agent which the effect of the action
+    /// reaches, None if it may reach any agent
+    pub fn get_halo(&self, action_ix: usize, id: Id, am: &AgentManager) -> Option<usize> {
+        self.actions[action_ix]
+            .halo
+            .as_ref()
+            .map(|halo| halo(id, am))
+    }
+
+    /// Stamina cost and cooldown of the action
+    pub fn get_costs(&self, action_ix: usize) -> (f32, usize) {
+        let action: &Action = &self.actions[action_ix];
+        (action.stamina_cost, action.cooldown)
+    }
+
+    /// Realises the movement or the effect of an action and charges its
+    /// stamina cost and cooldown
+    pub fn perform(&self, action_ix: usize, id: Id, am: &mut AgentManager, grid: &Grid) {
+        let action: &Action = &self.actions[action_ix];
+        match &action.movement {
+            Some(_) => {
+                let maybe_destination: Option<Point> =
+                    self.roll_destination(action_ix, id, am, grid);
+                am.move_to(id, maybe_destination);
             }
+            None => (action.effect)(id, am, grid),
+        }
+        am.use_stamina(id, action.stamina_cost);
+        am.set_cooldown(id, action_ix, action.cooldown);
+    }
+
+    /// Creates an action which moves the agent by (dx, dy), if it can pass
+    /// onto the cell and enter the terrain there. It only gets there with the
+    /// move probability of the terrain
+    pub fn step(dx: i64, dy: i64, config: &Config) -> Action {
+        let grid_side = config.grid_side;
+        let collision_detection = config.collision_detection;
+        let terrain_effects = config.terrain_effects;
+        let step = Action::movement(
+            Box::new(move |id, am, grid| {
+                if !am.can_move(id) {
+                    return false;
+                }
+                match am.get_position(id).shifted(dx, dy, grid_side) {
+                    Some(destination) => {
+                        let body_side = am.get_attributes(id).body_side;
+                        ActionContext::can_get_onto(id, destination, am, grid)
+                            && (!collision_detection
+                                || grid.is_subgrid_free(
+                                    destination,
+                                    body_size(body_side),
+                                    vec![id],
+                                    None,
+                                ))
+                    }
+                    None => false,
+                }
+            }),
+            Box::new(move |id, am, _| {
+                am.get_position(id)
+                    .shifted(dx, dy, grid_side)
+                    .map(Point::from)
+            }),
+        );
+        Action {
+            stamina_cost: config.step_stamina_cost,
+            terrain_effects: Some(terrain_effects),
+            ..step
+        }
+    }
+
+    /// Creates an action which moves the agent `dz` levels up, or down if
+    /// negative, staying above the same cell
+    pub fn climb(dz: i64, config: &Config) -> Action {
+        let levels = config.grid_levels();
+        let collision_detection = config.collision_detection;
+        let climb = Action::movement(
+            Box::new(move |id, am, grid| {
+                if !am.can_move(id) {
+                    return false;
+                }
+                match am.get_position(id).climbed(dz, levels) {
+                    Some(destination) => {
+                        let body_side = am.get_attributes(id).body_side;
+                        !collision_detection
+                            || grid.is_subgrid_free(
+                                destination,
+                                body_size(body_side),
+                                vec![id],
+                                None,
+                            )
+                    }
+                    None => false,
+                }
+            }),
+            Box::new(move |id, am, _| am.get_position(id).climbed(dz, levels).map(Point::from)),
+        );
+        Action {
+            stamina_cost: config.step_stamina_cost,
+            ..climb
+        }
+    }
+
+    /// Creates an action which moves the agent by its speed, scaled by the
+    /// terrain it starts on, in the direction (dx, dy), in one go. The whole
+    /// path must be free of other agents, obstacles and terrain the agent
+    /// can not enter, and agents only sprint if someone they chase or flee
+    /// from is in sight
+    pub fn sprint(dx: i64, dy: i64, config: &Config) -> Action {
+        let grid_side = config.grid_side;
+        let space = config.space;
+        let collision_detection = config.collision_detection;
+        let terrain_effects = config.terrain_effects;
+        let sprint = Action::movement(
+            Box::new(move |id, am, grid| {
+                if !am.can_move(id) {
+                    return false;
+                }
+                let position = am.get_position(id);
+                let attributes = am.get_attributes(id);
+                let speed = ActionContext::sprint_distance(id, am, grid, &terrain_effects) as i64;
+                let destination = match position.shifted(dx * speed, dy * speed, grid_side) {
+                    Some(destination) => destination,
+                    None => return false,
+                };
+                let is_passable = |k: i64| match position.shifted(dx * k, dy * k, grid_side) {
+                    Some(p) => am.can_enter(id, grid.terrain_at(p)) && grid.can_pass(position, p),
+                    None => false,
+                };
+                if !(1..speed).all(is_passable)
+                    || !ActionContext::can_get_onto(id, destination, am, grid)
+                {
+                    return false;
+                }
+                if collision_detection
+                    && !grid.is_path_free(
+                        position,
+                        (dx, dy),
+                        speed as usize,
+                        attributes.body_side,
+                        vec![id],
+                    )
+                {
+                    return false;
+                }
+                ActionContext::is_chase_in_sight(id, am, grid, space)
+            }),
+            Box::new(move |id, am, grid| {
+                let speed = ActionContext::sprint_distance(id, am, grid, &terrain_effects) as i64;
+                am.get_position(id)
+                    .shifted(dx * speed, dy * speed, grid_side)
+                    .map(Point::from)
+            }),
+        );
+        Action {
+            duration: config.sprint_duration,
+            stamina_cost: config.sprint_stamina_cost,
+            terrain_effects: Some(terrain_effects),
+            ..sprint
+        }
+    }
+
+    /// Number of cells the agent covers in a sprint from where it stands
+    fn sprint_distance(
+        id: Id,
+        am: &AgentManager,
+        grid: &Grid,
+        terrain_effects: &TerrainEffects,
+    ) -> usize {
+        let terrain = grid.terrain_at(am.get_position(id));
+        let distance: f32 = am.get_speed(id) as f32 * terrain_effects.of(terrain).speed_factor;
+        cmp::max(distance.round() as usize, 1)
+    }
+
+    /// Whether the agent can pass onto the destination and enter the terrain
+    /// there
+    fn can_get_onto(id: Id, destination: Position, am: &AgentManager, grid: &Grid) -> bool {
+        am.can_enter(id, grid.terrain_at(destination))
+            && grid.can_pass(am.get_position(id), destination)
+    }
+
+    /// Whether someone the agent chases, or flees from, is within its
+    /// vision radius, which in continuous space is measured between the
+    /// edges of their bodies
+    fn is_chase_in_sight(id: Id, am: &AgentManager, grid: &Grid, space: Space) -> bool {
+        let position = am.get_position(id);
+        let vision_radius = am.get_attributes(id).vision_radius;
+        if am.can_tag(id) {
+            let (vision_side, is_out_of_sight) =
+                ActionContext::reach(id, am, space, 2 * vision_radius + 1, vision_radius as f32);
+            let ignore_tagged = |other: Id| is_out_of_sight(other) || !am.can_tag_target(id, other);
+            grid.is_subgrid_occupied(
+                position,
+                cube_size(vision_side),
+                vec![id],
+                Some(&ignore_tagged),
+            )
+        } else {
+            am.get_it_ids().iter().any(|&it_id| match space {
+                Space::Discrete => grid.distance(am.get_position(it_id), position) <= vision_radius,
+                Space::Continuous => am.gap_between(id, it_id) <= vision_radius as f32,
+            })
+        }
+    }
+
+    /// Side of the square searched for the agents within reach of the agent,
+    /// and whether an agent found there is out of reach after all. In
+    /// discrete space everyone in the square of side `side` is within reach,
+    /// in continuous space the gap between the edges of the bodies may be
+    /// at most `gap`
+    fn reach(
+        id: Id,
+        am: &AgentManager,
+        space: Space,
+        side: usize,
+        gap: f32,
+    ) -> (usize, impl Fn(Id) -> bool + '_) {
+        let window: usize = match space {
+            Space::Discrete => side,
+            Space::Continuous => am.gap_window(id, gap),
         };
-        let left_step_effect: Effect = |id, am, _| {
-            let mut position = am.get_position(id);
-            position.x -= 1;
-            am.set_position(id, position);
-        };
-        let left_step: Action = Action {
-            precond: left_step_precond,
-            effect: left_step_effect,
-        };
+        let is_out_of_reach =
+            move |other: Id| matches!(space, Space::Continuous) && am.gap_between(id, other) > gap;
+        (window, is_out_of_reach)
+    }
 
-        let right_step_precond: Precondition = |id, am, grid| {
-            let position = am.get_position(id);
-            if position.x == GRID_SIDE - 1 {
-                return false;
-            }
-            if COLLSION_DETECTION {
-                grid.is_subgrid_free(
-                    Position {
-                        x: position.x + 1,
-                        y: position.y,
-                    },
-                    STEP_SG_SIDE,
-                    STEP_SG_SIDE,
-                    vec![id],
-                    None,
-                )
+    /// Side of the square searched for the agents within the agent's tag
+    /// reach, which in continuous space is half of `tag_reach` between the
+    /// edges of their bodies, like the half-width of the square in discrete
+    /// space
+    fn tag_reach(id: Id, am: &AgentManager, space: Space) -> (usize, impl Fn(Id) -> bool + '_) {
+        let tag_reach: usize = am.get_attributes(id).tag_reach;
+        ActionContext::reach(id, am, space, tag_reach, tag_reach as f32 / 2.0)
+    }
+
+    /// Creates an action for continuous space which turns the agent by
+    /// `turn` radians and moves it forward, one cell or, when sprinting, by
+    /// its speed, scaled by the terrain it starts on. The agent must be able
+    /// to pass onto the cell and enter the terrain at every cell's length
+    /// along the way and, with collision detection, its body must fit there
+    pub fn steer(turn: f32, sprint: bool, config: &Config) -> Action {
+        let grid_side = config.grid_side;
+        let space = config.space;
+        let collision_detection = config.collision_detection;
+        let terrain_effects = config.terrain_effects;
+        let distance = move |id: Id, am: &AgentManager, grid: &Grid| {
+            let speed_factor = terrain_effects
+                .of(grid.terrain_at(am.get_position(id)))
+                .speed_factor;
+            if sprint {
+                am.get_speed(id) as f32 * speed_factor
             } else {
-                true
+                speed_factor
             }
         };
-        let right_step_effect: Effect = |id, am, _| {
-            let mut position = am.get_position(id);
-            position.x += 1;
-            am.set_position(id, position);
-        };
-        let right_step: Action = Action {
-            precond: right_step_precond,
-            effect: right_step_effect,
+        let steer = Action::movement(
+            Box::new(move |id, am, grid| {
+                if !am.can_move(id) {
+                    return false;
+                }
+                let point = am.get_point(id);
+                let heading = am.get_heading(id) + turn;
+                let distance = distance(id, am, grid);
+                let destination = match point.moved(heading, distance, grid_side) {
+                    Some(destination) => destination,
+                    None => return false,
+                };
+                let position = am.get_position(id);
+                let mut covered: f32 = 0.0;
+                while covered < distance {
+                    covered = (covered + 1.0).min(distance);
+                    match point.moved(heading, covered, grid_side) {
+                        Some(p)
+                            if am.can_enter(id, grid.terrain_at(p.cell()))
+                                && grid.can_pass(position, p.cell())
+                                && (!collision_detection || am.is_free_for(id, p, grid)) => {}
+                        _ => return false,
+                    }
+                }
+                ActionContext::can_get_onto(id, destination.cell(), am, grid)
+                    && (!sprint || ActionContext::is_chase_in_sight(id, am, grid, space))
+            }),
+            Box::new(move |id, am, grid| {
+                am.get_point(id)
+                    .moved(am.get_heading(id) + turn, distance(id, am, grid), grid_side)
+            }),
+        );
+        let steer = Action {
+            terrain_effects: Some(terrain_effects),
+            ..steer
         };
-
-        let up_step_precond: Precondition = |id, am, grid| {
-            let position = am.get_position(id);
-            if position.y == 0 {
-                return false;
+        if sprint {
+            Action {
+                duration: config.sprint_duration,
+                stamina_cost: config.sprint_stamina_cost,
+                ..steer
             }
-            if COLLSION_DETECTION {
-                grid.is_subgrid_free(
-                    Position {
-                        x: position.x,
-                        y: position.y - 1,
-                    },
-                    STEP_SG_SIDE,
-                    STEP_SG_SIDE,
-                    vec![id],
-                    None,
-                )
-            } else {
-                true
+        } else {
+            Action {
+                stamina_cost: config.step_stamina_cost,
+                ..steer
             }
-        };
-        let up_step_effect: Effect = |id, am, _| {
-            let mut position = am.get_position(id);
-            position.y -= 1;
-            am.set_position(id, position);
-        };
-        let up_step: Action = Action {
-            precond: up_step_precond,
-            effect: up_step_effect,
-        };
+        }
+    }
 
-        let down_step_precond: Precondition = |id, am, grid| {
-            let position = am.get_position(id);
-            if position.y == GRID_SIDE - 1 {
-                return false;
+    pub fn new(config: &Config) -> ActionContext {
+        // Movements depend on the space, the other actions do not
+        let (mut actions, mut mean_preferences): (Vec<Action>, Vec<f32>) = match config.space {
+            Space::Discrete => {
+                let directions = config.topology.directions();
+                let mut steps: Vec<Action> = directions
+                    .iter()
+                    .map(|&(dx, dy)| ActionContext::step(dx, dy, config))
+                    .collect();
+                // Climbing up and down is only possible with several levels
+                if config.grid_levels() > 1 {
+                    steps.push(ActionContext::climb(1, config));
+                    steps.push(ActionContext::climb(-1, config));
+                }
+                let step_count: usize = steps.len();
+                (steps, vec![0.5; step_count])
             }
-            if COLLSION_DETECTION {
-                grid.is_subgrid_free(
-                    Position {
-                        x: position.x,
-                        y: position.y + 1,
-                    },
-                    STEP_SG_SIDE,
-                    STEP_SG_SIDE,
-                    vec![id],
-                    None,
+            Space::Continuous => {
+                let angle: f32 = config.steer_angle.to_radians();
+                (
+                    vec![
+                        ActionContext::steer(-angle, false, config),
+                        ActionContext::steer(0.0, false, config),
+                        ActionContext::steer(angle, false, config),
+                    ],
+                    vec![0.5, 0.5, 0.5],
                 )
-            } else {
-                true
             }
         };
-        let down_step_effect: Effect = |id, am, _| {
-            let mut position = am.get_position(id);
-            position.y += 1;
-            am.set_position(id, position);
-        };
-        let down_step: Action = Action {
-            precond: down_step_precond,
-            effect: down_step_effect,
+        // Sprints are only added if they can take some agent further than a step
+        let can_sprint: bool =
+            config.it_speed_bonus > 0 || config.agent_types.iter().any(|t| t.speed.1 > 1);
+        let sprints: Vec<Action> = match config.space {
+            _ if !can_sprint => vec![],
+            Space::Discrete => config
+                .topology
+                .directions()
+                .iter()
+                .map(|&(dx, dy)| ActionContext::sprint(dx, dy, config))
+                .collect(),
+            Space::Continuous => vec![ActionContext::steer(0.0, true, config)],
         };
 
-        let do_nothing_precond: Precondition = |_, _, _| true;
-        let do_nothing_effect: Effect = |_, _, _| {};
         let do_nothing: Action = Action {
-            precond: do_nothing_precond,
-            effect: do_nothing_effect,
+            stamina_cost: -config.rest_stamina_gain,
+            ..Action::movement(Box::new(|_, _, _| true), Box::new(|_, _, _| None))
         };
 
-        let tag_precond: Precondition = |id, am, grid| {
-            if am.get_is_it(id) {
-                let mut excluded_ids = vec![id];
-                let maybe_tagged_by: Option<Id> = am.maybe_get_tagged_by(id);
-                if let Some(tagged_by) = maybe_tagged_by {
-                    excluded_ids.push(tagged_by);
-                }
-                let am_immut = &*am;
-                let ignore_tagged = move |id: Id| am_immut.get_is_it(id);
+        let capture = config.rules.capture;
+        let space = config.space;
+        let tag_precond: Precondition = Box::new(move |id, am, grid| {
+            if am.can_tag(id) {
+                let (tag_side, is_out_of_reach) = ActionContext::tag_reach(id, am, space);
+                let ignore_tagged = |other: Id| {
+                    is_out_of_reach(other)
+                        || !am.can_tag_target(id, other)
+                        || am.is_safe(other, grid)
+                };
 
                 grid.is_subgrid_occupied(
                     am.get_position(id),
-                    TAG_SG_SIDE,
-                    TAG_SG_SIDE,
-                    excluded_ids,
+                    cube_size(tag_side),
+                    vec![id],
                     Some(&ignore_tagged),
                 )
             } else {
                 false
             }
+        });
+
+        let tag_effect: Effect = Box::new(move |id, am, grid| {
+            let ids: Vec<Id> = {
+                let am_immut = &*am;
+                let (tag_side, is_out_of_reach) = ActionContext::tag_reach(id, am_immut, space);
+                let ignore_tagged = move |other: Id| {
+                    is_out_of_reach(other)
+                        || !am_immut.can_tag_target(id, other)
+                        || am_immut.is_safe(other, grid)
+                };
+                grid.get_subgrid_occupiers(
+                    am_immut.get_position(id),
+                    cube_size(tag_side),
+                    vec![id],
+                    Some(&ignore_tagged),
+                )
+            };
+
+            if let Some(&target_id) = ids.choose(&mut am.rng) {
+                // What tagging does to both agents is up to the rules, e.g.
+                // in classic tag the tagger stops being 'it'
+                am.trigger(target_id, Trigger::Tagged);
+                if am.trigger(id, Trigger::Tag) {
+                    am.reset_last_untagged(id);
+                }
+                am.record_tag(id, target_id);
+                if capture {
+                    am.capture(id, target_id);
+                }
+                am.increment_tagged();
+            }
+        });
+        let tag: Action = Action {
+            cooldown: config.tag_cooldown,
+            halo: Some(Box::new(move |id, am| {
+                ActionContext::tag_reach(id, am, space).0
+            })),
+            ..Action::new(tag_precond, tag_effect)
         };
 
-        let tag_effect: Effect = |id, am, grid| {
-            let mut excluded_ids = vec![id];
-            let maybe_tagged_by: Option<Id> = am.maybe_get_tagged_by(id);
-            if let Some(tagged_by) = maybe_tagged_by {
-                excluded_ids.push(tagged_by);
+        // Agents can release teammates within their reach, e.g. unfreeze
+        // frozen runners in freeze tag
+        let release_precond: Precondition = Box::new(move |id, am, grid| {
+            if !am.can_release(id) {
+                return false;
             }
-            let am_immut = &*am;
-            let ignore_tagged = move |id: Id| am_immut.get_is_it(id);
-            let ids: Vec<Id> = grid.get_subgrid_occupiers(
+            let (tag_side, is_out_of_reach) = ActionContext::tag_reach(id, am, space);
+            let ignore_unreleasable =
+                |other: Id| is_out_of_reach(other) || !am.can_release_target(id, other);
+            grid.is_subgrid_occupied(
                 am.get_position(id),
-                TAG_SG_SIDE,
-                TAG_SG_SIDE,
-                excluded_ids,
-                Some(&ignore_tagged),
-            );
-            
-            if let Some(target_id) = ids.choose(&mut am.rng) {
-                am.reset_last_untagged(id);
-                am.set_is_it(id, false);
-                am.set_is_it(*target_id, true);
-                am.set_tagged_by(id, None);
-                am.set_tagged_by(*target_id, Some(id));
-                am.increment_tagged();
+                cube_size(tag_side),
+                vec![id],
+                Some(&ignore_unreleasable),
+            )
+        });
+        let release_effect: Effect = Box::new(move |id, am, grid| {
+            let ids: Vec<Id> = {
+                let am_immut = &*am;
+                let (tag_side, is_out_of_reach) = ActionContext::tag_reach(id, am_immut, space);
+                let ignore_unreleasable = move |other: Id| {
+                    is_out_of_reach(other) || !am_immut.can_release_target(id, other)
+                };
+                grid.get_subgrid_occupiers(
+                    am_immut.get_position(id),
+                    cube_size(tag_side),
+                    vec![id],
+                    Some(&ignore_unreleasable),
+                )
+            };
+            if let Some(&target_id) = ids.choose(&mut am.rng) {
+                am.trigger(target_id, Trigger::Released);
             }
+        });
+        let release: Action = Action {
+            halo: Some(Box::new(move |id, am| {
+                ActionContext::tag_reach(id, am, space).0
+            })),
+            ..Action::new(release_precond, release_effect)
         };
-        let tag: Action = Action {
-            precond: tag_precond,
-            effect: tag_effect,
-        };
-        let actions: Vec<Action> = vec![left_step, right_step, up_step, down_step, do_nothing, tag];
+
+        actions.push(do_nothing);
+        mean_preferences.push(0.1);
+        actions.push(tag);
+        mean_preferences.push(0.9);
+        mean_preferences.extend(sprints.iter().map(|_| 0.1));
+        actions.extend(sprints);
+        if !config.rules.releasing.is_empty() {
+            actions.push(release);
+            mean_preferences.push(0.9);
+        }
+        ActionContext::from_actions(actions, mean_preferences)
+    }
+
+    /// Context of the given actions, which agents prefer as much as their
+    /// mean preferences say on average
+    pub fn from_actions(actions: Vec<Action>, mean_preferences: Vec<f32>) -> ActionContext {
         let action_count = actions.len();
         ActionContext {
             actions,
-            mean_preferences: vec![0.5, 0.5, 0.5, 0.5, 0.1, 0.9],
+            mean_preferences,
             action_count,
         }
     }
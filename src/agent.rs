@@ -1,169 +1,1154 @@
-use crate::{AGENT_NUM_UPPER_BOUND, STEP_SG_SIDE, LAST_UNTAGGED_DISPLAY_LENGTH};
+use crate::LAST_UNTAGGED_DISPLAY_LENGTH;
 
 use crate::action::*;
+use crate::config::{AgentType, Config, GameMode, Space, TagBackRules, Terrain};
 use crate::display::RenderObject;
-use crate::grid::{Grid, Position, PositionChange};
+use crate::engine::Termination;
+use crate::grid::{body_size, Grid, Point, Position, PositionChange, Region};
+use crate::state::{Rules, State, Trigger};
 
+use rand::distributions::{Distribution, WeightedIndex};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 
-pub type Id = u32;
+/// Side of the square tiles the grid is split into when committing actions,
+/// see `AgentManager::commit_partitioned`
+const TILE_SIDE: usize = 16;
 
-#[derive(Clone)]
-struct Agent {
-    /// Id for an agent
-    id: Id,
-    /// Cartesian coordinates
-    position: Position,
-    /// Has it been tagged most recently
-    is_it: bool,
-    /// If it is 'it', who was it tagged by
-    tagged_by: Option<u32>,
-    /// Preferences on action choice
-    pref: Vec<f32>,
-    /// Next action, used for concurrency
-    next_action: Option<Effect>,
+/// Handle of an agent: the index of its slot in the agent manager, and the
+/// generation of the slot. A slot's generation changes when its agent is
+/// removed, so that stale handles are never mistaken for a newer agent
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Id {
+    index: u32,
+    generation: u32,
+}
+
+impl Id {
+    /// Index and generation packed into one number, e.g. for hashing
+    fn as_u64(self) -> u64 {
+        (u64::from(self.generation) << 32) | u64::from(self.index)
+    }
+}
+
+#[cfg(test)]
+impl Id {
+    /// Handle of a made-up agent, for tests which fill grids by hand
+    pub fn test(index: u32) -> Id {
+        Id {
+            index,
+            generation: 0,
+        }
+    }
+}
+
+/// Place of an agent in the buffers of `AgentManager::agents`, looked up by
+/// `Id::index`
+#[derive(Clone, Copy)]
+struct Slot {
+    generation: u32,
+    /// Index in the buffers, or None if the slot is free
+    agent_ix: Option<usize>,
+}
+
+/// Attributes of an agent, drawn from its type when it is created
+#[derive(Clone, Copy)]
+pub struct Attributes {
+    /// Index of the agent's type in `Config::agent_types`
+    pub agent_type: usize,
+    /// Side length of the square which must be free around a step destination
+    pub body_side: usize,
+    /// Side length of the square around the agent in which it can tag others
+    pub tag_reach: usize,
+    /// Number of cells covered by a sprint
+    pub speed: usize,
+    /// Distance up to which the agent notices others
+    pub vision_radius: usize,
+}
+
+/// All agents in structure-of-arrays layout: the agent at index i has its
+/// data at index i of every buffer, or at `i * action_count..(i + 1) *
+/// action_count` of the per-action buffers
+#[derive(Default)]
+struct Agents {
+    /// Id for every agent
+    ids: Vec<Id>,
+    /// Cartesian coordinates, i.e. the cell containing `points`
+    positions: Vec<Position>,
+    /// Exact position, which is a cell's position in discrete space
+    points: Vec<Point>,
+    /// Direction of the last movement, in radians
+    headings: Vec<f32>,
+    /// Distance covered by the last movement, 0 if the agent stood still
+    speeds: Vec<f32>,
+    /// State in the game, e.g. whether it is 'it'
+    states: Vec<State>,
+    /// Number of steps the agent has been in its current state
+    state_for: Vec<usize>,
+    /// Number of steps the agent has been in a safe zone without leaving it
+    safe_for: Vec<usize>,
+    /// Index of the agent's team, all agents are in team 0 unless playing team tag
+    teams: Vec<usize>,
+    /// Most recent taggers of the agent and the steps at which they tagged
+    /// it, newest first
+    tagged_by: Vec<VecDeque<(Id, usize)>>,
+    /// Step from which each action can be chosen again, per action. Keeping
+    /// the step rather than counting down makes cooldowns independent of how
+    /// often the agent is activated in a step
+    cooldowns: Vec<usize>,
+    /// Consumed by actions with a stamina cost, restored by resting
+    stamina: Vec<f32>,
+    /// Attributes drawn from the agent's type
+    attributes: Vec<Attributes>,
     /// Tells how many time steps ago the agent was tagged,
     /// only used for visualisation 
-    last_un_tagged: usize,
+    last_un_tagged: Vec<usize>,
+}
+
+/// The part of the agents' data which is written while they decide on
+/// their next action, laid out like `Agents`. It is moved out of the
+/// manager while agents decide, since preconditions only read the rest
+#[derive(Default)]
+struct Decisions {
+    /// Preferences on action choice, per action
+    prefs: Vec<f32>,
+    /// Index of the next action, used for concurrency. For actions which
+    /// take several steps, this is the action currently being performed
+    next_action: Vec<Option<usize>>,
+    /// Steps left before the effect of `next_action` is realised
+    remaining_steps: Vec<usize>,
+}
+
+impl Decisions {
+    fn push(&mut self, prefs: &[f32]) {
+        self.prefs.extend_from_slice(prefs);
+        self.next_action.push(None);
+        self.remaining_steps.push(0);
+    }
+
+    fn swap_remove(&mut self, index: usize, action_count: usize) {
+        swap_remove_row(&mut self.prefs, index, action_count);
+        self.next_action.swap_remove(index);
+        self.remaining_steps.swap_remove(index);
+    }
+}
+
+/// Moves the agent to the destination of a movement, or stops it if there
+/// is none, updating its heading and speed. Returns the change of cell, if
+/// the agent left its cell
+fn move_to(
+    id: Id,
+    maybe_destination: Option<Point>,
+    position: &mut Position,
+    point: &mut Point,
+    heading: &mut f32,
+    speed: &mut f32,
+) -> Option<PositionChange> {
+    let after: Point = match maybe_destination {
+        Some(after) => after,
+        None => {
+            *speed = 0.0;
+            return None;
+        }
+    };
+    *speed = point.distance(after);
+    if *speed > 0.0 {
+        *heading = (after.y - point.y).atan2(after.x - point.x);
+    }
+    *point = after;
+    let cell: Position = after.cell();
+    if cell == *position {
+        return None;
+    }
+    let before: Position = std::mem::replace(position, cell);
+    Some(PositionChange {
+        id,
+        before,
+        after: cell,
+    })
+}
+
+/// Removes the `width` values at `index * width` by moving the last
+/// `width` values there, like `Vec::swap_remove` for flattened rows
+fn swap_remove_row<T: Copy>(v: &mut Vec<T>, index: usize, width: usize) {
+    let last = v.len() - width;
+    v.copy_within(last.., index * width);
+    v.truncate(last);
+}
+
+/// Hashes the values into a seed with SplitMix64, whose output, unlike that
+/// of `DefaultHasher`, is fixed and so the same with every Rust release
+fn hash_seed(values: &[u64]) -> u64 {
+    values.iter().fold(0, |hash, &value| {
+        let mut z: u64 = (hash ^ value).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    })
+}
+
+/// Advances the per-step counters of an agent
+fn tick_counters(
+    grid: &Grid,
+    position: Position,
+    last_un_tagged: &mut usize,
+    state_for: &mut usize,
+    safe_for: &mut usize,
+) {
+    *last_un_tagged += 1;
+    *state_for += 1;
+    *safe_for = if grid.is_safe(position) {
+        *safe_for + 1
+    } else {
+        0
+    };
 }
 
 pub struct AgentManager {
-    /// All agents
-    agents: Vec<Agent>,
-    /// Map from Ids to agent index, e.g. used
-    /// to find an agent from inside Grid
-    id_map: HashMap<Id, usize>,
+    /// All agents, except for their decisions
+    agents: Agents,
+    /// Decisions of all agents on their next action
+    decisions: Decisions,
+    /// Slots indexed by `Id::index`, which map Ids to agent index, e.g.
+    /// used to find an agent from inside Grid
+    slots: Vec<Slot>,
+    /// Indices of the slots of removed agents, which are reused first
+    free_slots: Vec<u32>,
     /// Recently changed coordinates which
     /// is used for quick Grid update
     position_log: Vec<PositionChange>,
     /// Number of actions possible
     action_count: usize,
+    /// Upper limit of an agent's stamina, which is also its starting stamina
+    max_stamina: f32,
+    /// Kinds of agents, from which the attributes of new agents are drawn
+    agent_types: Vec<AgentType>,
+    /// Distribution of agent types in the population
+    agent_type_dist: WeightedIndex<f32>,
+    /// Number of cells of the grid, no agents are added past it
+    agent_num_upper_bound: usize,
+    /// Speed added to the speed of agents which are 'it'
+    it_speed_bonus: usize,
+    /// Rules of the game being played
+    game_mode: GameMode,
+    /// Abilities of agents in every state and transitions between states
+    rules: Rules,
+    /// Number of steps after which agents in a safe zone can be tagged again
+    safe_zone_stay_limit: Option<usize>,
+    /// Number of captures made by every team in team tag
+    team_scores: Vec<usize>,
+    /// Side length of the grid agents are placed on
+    grid_side: usize,
+    /// Largest body side any agent can have
+    max_body_side: usize,
+    /// Space agents move in
+    space: Space,
     /// Number of times the 'Tag' action has been used
     tagged_count: usize,
+    /// Restrictions on tag-backs and re-tags
+    tag_back_rules: TagBackRules,
+    /// Number of times actions have been performed
+    step: usize,
+    /// Number of activations before the current one in this step, which
+    /// sets apart the rolls of agents activated several times in a step
+    activation: usize,
+    /// Number of times agents have changed from one state to another
+    transition_counts: HashMap<(State, State), usize>,
+    /// Ids of the agents which are 'it', so that runners can look out for
+    /// them without searching the grid
+    it_ids: Vec<Id>,
+    /// Seed of `rng` and of the random number generators of the agents
+    seed: u64,
     pub rng: StdRng,
 }
 
 impl AgentManager {
-    pub fn new(
+    /// Agent manager with the population of the config, seeded with the
+    /// config's seed or a random one
+    pub fn new(config: &Config, ac: &ActionContext, grid: &mut Grid) -> AgentManager {
+        AgentManager::with_seed(config, ac, grid, config.seed.unwrap_or_else(rand::random))
+    }
+
+    /// Agent manager with the population of the config, whose random
+    /// numbers all follow from the seed
+    pub fn with_seed(
+        config: &Config,
         ac: &ActionContext,
         grid: &mut Grid,
-        num_agents: usize,
-        num_it: usize,
+        seed: u64,
     ) -> AgentManager {
-        let rng = rand::rngs::StdRng::from_entropy();
+        let rng = StdRng::seed_from_u64(seed);
         let action_count = ac.action_count;
         let mut am = AgentManager {
-            agents: vec![],
-            id_map: HashMap::new(),
+            agents: Agents::default(),
+            decisions: Decisions::default(),
+            slots: vec![],
+            free_slots: vec![],
             position_log: vec![],
             action_count,
+            max_stamina: config.max_stamina,
+            agent_types: config.agent_types.clone(),
+            agent_type_dist: WeightedIndex::new(config.agent_types.iter().map(|t| t.share))
+                .unwrap(),
+            agent_num_upper_bound: config.grid_side * config.grid_side * config.grid_levels(),
+            it_speed_bonus: config.it_speed_bonus,
+            game_mode: config.game_mode,
+            rules: config.rules.clone(),
+            safe_zone_stay_limit: config.safe_zone_stay_limit,
+            team_scores: vec![0; config.teams.len()],
+            grid_side: config.grid_side,
+            max_body_side: config
+                .agent_types
+                .iter()
+                .map(|t| t.body_side.1)
+                .max()
+                .unwrap(),
+            space: config.space,
             tagged_count: 0,
+            tag_back_rules: config.tag_back_rules,
+            step: 0,
+            activation: 0,
+            transition_counts: HashMap::new(),
+            it_ids: vec![],
+            seed,
             rng,
         };
-        for i in 0..num_agents {
-            am.add_agent(i < num_it, None, None, grid);
+        if config.game_mode == GameMode::Team {
+            for (team_ix, team) in config.teams.iter().enumerate() {
+                for _ in 0..team.size {
+                    am.add_agent(State::Runner, team_ix, team.region, None, grid);
+                }
+            }
+        } else {
+            for i in 0..config.num_agents {
+                let state = if i < config.num_agents_it {
+                    State::It
+                } else {
+                    State::Runner
+                };
+                am.add_agent(state, 0, Region::WHOLE, None, grid);
+            }
         }
         am
     }
 
-    fn add_agent(
+    /// Adds an agent at the given position, or at a random free position in
+    /// the region. Returns its id, or None if the position is not free, no
+    /// free position was found or the grid is full. Pending position changes
+    /// are applied to the grid first, so this must not be called while
+    /// actions are being performed
+    pub fn add_agent(
         &mut self,
-        is_it: bool,
-        tagged_by: Option<u32>,
+        state: State,
+        team: usize,
+        region: Region,
         maybe_position: Option<Position>,
         grid: &mut Grid,
-    ) {
-        if self.agents.len() == AGENT_NUM_UPPER_BOUND {
-            return;
+    ) -> Option<Id> {
+        if self.agents.ids.len() == self.agent_num_upper_bound {
+            return None;
         }
+        grid.update(self.flush_log());
+        let attributes: Attributes = self.rand_attributes();
+        let position: Position = match maybe_position {
+            Some(position) => {
+                if !self.is_room_for(position, attributes, vec![], grid) {
+                    return None;
+                }
+                position
+            }
+            None => self.rand_pos(attributes, region, grid)?,
+        };
         let id: Id = self.new_id();
-        self.id_map.insert(id, self.agents.len());
-        let pref: Vec<f32> = (0..self.action_count)
-            .map(|_| self.rng.gen::<f32>())
-            .collect();
-        if let Some(position) = maybe_position {
-            self.agents.push(Agent {
-                id,
-                position,
-                is_it,
-                tagged_by,
-                pref,
-                next_action: None,
-                last_un_tagged: LAST_UNTAGGED_DISPLAY_LENGTH + 1,
-            });
-            grid.set(position, id);
-        } else {
-            let maybe_rand_pos: Option<Position> = self.rand_pos(grid);
-            if let Some(position) = maybe_rand_pos {
-                self.agents.push(Agent {
-                    id,
-                    position,
-                    is_it,
-                    tagged_by,
-                    pref,
-                    next_action: None,
-                    last_un_tagged: LAST_UNTAGGED_DISPLAY_LENGTH + 1,
-                });
-                grid.set(position, id);
-            } else {
-                return;
+        self.slots[id.index as usize].agent_ix = Some(self.agents.ids.len());
+        let agents: &mut Agents = &mut self.agents;
+        agents.ids.push(id);
+        agents.positions.push(position);
+        agents.points.push(Point::from(position));
+        agents.headings.push(
+            self.rng
+                .gen_range(-std::f32::consts::PI, std::f32::consts::PI),
+        );
+        agents.speeds.push(0.0);
+        agents.states.push(state);
+        agents.state_for.push(0);
+        agents.safe_for.push(0);
+        agents.teams.push(team);
+        agents.tagged_by.push(VecDeque::new());
+        agents.cooldowns.extend((0..self.action_count).map(|_| 0));
+        agents.stamina.push(self.max_stamina);
+        agents.attributes.push(attributes);
+        agents.last_un_tagged.push(LAST_UNTAGGED_DISPLAY_LENGTH + 1);
+        let rng: &mut StdRng = &mut self.rng;
+        let prefs: Vec<f32> = (0..self.action_count).map(|_| rng.gen::<f32>()).collect();
+        self.decisions.push(&prefs);
+        grid.add(position, id);
+        if state == State::It {
+            self.it_ids.push(id);
+        }
+        Some(id)
+    }
+
+    /// Removes the agent from the game and the grid, returns whether it was
+    /// there. Like `add_agent`, this must not be called while actions are
+    /// being performed
+    pub fn remove_agent(&mut self, id: Id, grid: &mut Grid) -> bool {
+        if !self.contains(id) {
+            return false;
+        }
+        grid.update(self.flush_log());
+        let slot: &mut Slot = &mut self.slots[id.index as usize];
+        let index: usize = slot.agent_ix.take().unwrap();
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free_slots.push(id.index);
+        let agents: &mut Agents = &mut self.agents;
+        let position: Position = agents.positions.swap_remove(index);
+        agents.points.swap_remove(index);
+        agents.headings.swap_remove(index);
+        agents.speeds.swap_remove(index);
+        let state: State = agents.states.swap_remove(index);
+        agents.ids.swap_remove(index);
+        agents.state_for.swap_remove(index);
+        agents.safe_for.swap_remove(index);
+        agents.teams.swap_remove(index);
+        agents.tagged_by.swap_remove(index);
+        swap_remove_row(&mut agents.cooldowns, index, self.action_count);
+        agents.stamina.swap_remove(index);
+        agents.attributes.swap_remove(index);
+        agents.last_un_tagged.swap_remove(index);
+        self.decisions.swap_remove(index, self.action_count);
+        if let Some(&moved) = agents.ids.get(index) {
+            self.slots[moved.index as usize].agent_ix = Some(index);
+        }
+        grid.remove(position, id);
+        if state == State::It {
+            self.it_ids.retain(|&it_id| it_id != id);
+        }
+        true
+    }
+
+    /// Ids of the agents in the state
+    pub fn get_ids_in_state(&self, state: State) -> Vec<Id> {
+        self.agents
+            .ids
+            .iter()
+            .zip(&self.agents.states)
+            .filter(|&(_, &s)| s == state)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// Lets the agent choose its next action, or carry on with the one it
+    /// is busy with. Only the agent's decisions are written, which are
+    /// passed separately so that this can run in parallel
+    #[allow(clippy::too_many_arguments)]
+    fn decide(
+        &self,
+        ac: &ActionContext,
+        grid: &Grid,
+        id: Id,
+        pref: &mut [f32],
+        next_action: &mut Option<usize>,
+        remaining_steps: &mut usize,
+        rng: &mut StdRng,
+    ) {
+        if let (Some(action_ix), true) = (*next_action, *remaining_steps > 0) {
+            // Still busy with a multi-step action, which is abandoned
+            // if it is no longer allowed when it is about to finish
+            *remaining_steps -= 1;
+            if *remaining_steps == 0 && !ac.is_allowed(action_ix, id, self, grid) {
+                *next_action = None;
             }
+            return;
+        }
+        AgentManager::update_preference(pref, ac.get_mean_preferences(), self.action_count, rng);
+        let ordering = &*AgentManager::get_actions_ordering(pref, rng);
+        *next_action = ac.maybe_get_allowed_action(ordering, id, self, grid);
+        if let Some(action_ix) = *next_action {
+            *remaining_steps = ac.get_duration(action_ix).saturating_sub(1);
         }
     }
 
-    pub fn perform_actions(&mut self, grid: &Grid, ac: &ActionContext) {
-        let mean_preferences = ac.get_mean_preferences();
+    /// Lets all agents decide in parallel on the same state. Their decisions
+    /// are moved out of the manager meanwhile, which only moves the handles
+    fn decide_all(&mut self, grid: &Grid, ac: &ActionContext) {
         let action_count = self.action_count;
+        let mut decisions: Decisions = std::mem::take(&mut self.decisions);
         let s = &*self;
-        let mut agents = self.agents.clone();
-        let v = move |agent: &mut Agent| {
-            let mut rng = rand::thread_rng();
-            AgentManager::update_preference(agent, mean_preferences, action_count, &mut rng);
-            let ordering = &*AgentManager::get_actions_ordering(agent, &mut rng);
-            agent.next_action = ac.maybe_get_allowed_effect(ordering, agent.id, s, grid);
+        decisions
+            .prefs
+            .par_chunks_mut(action_count)
+            .zip(decisions.next_action.par_iter_mut())
+            .zip(decisions.remaining_steps.par_iter_mut())
+            .zip(s.agents.ids.par_iter())
+            .for_each(|(((pref, next_action), remaining_steps), &id)| {
+                let mut rng: StdRng = s.decision_rng(id);
+                s.decide(ac, grid, id, pref, next_action, remaining_steps, &mut rng)
+            });
+        self.decisions = decisions;
+    }
+
+    /// Advances the per-step counters of all agents, and makes the timed
+    /// state transitions which have run out
+    fn tick(&mut self, grid: &Grid) {
+        let agent_count: usize = self.agents.ids.len();
+
+        // Counters only concern the agent itself, so they are updated in
+        // parallel
+        let agents: &mut Agents = &mut self.agents;
+        agents
+            .last_un_tagged
+            .par_iter_mut()
+            .zip(agents.state_for.par_iter_mut())
+            .zip(
+                agents
+                    .safe_for
+                    .par_iter_mut()
+                    .zip(agents.positions.par_iter()),
+            )
+            .for_each(|((last_un_tagged, state_for), (safe_for, &position))| {
+                tick_counters(grid, position, last_un_tagged, state_for, safe_for)
+            });
+        for i in 0..agent_count {
+            self.make_timed_transition(i);
+        }
+    }
+
+    /// Ticks a single agent, like `tick` does for all of them
+    fn tick_agent(&mut self, i: usize, grid: &Grid) {
+        let agents: &mut Agents = &mut self.agents;
+        tick_counters(
+            grid,
+            agents.positions[i],
+            &mut agents.last_un_tagged[i],
+            &mut agents.state_for[i],
+            &mut agents.safe_for[i],
+        );
+        self.make_timed_transition(i);
+    }
+
+    /// Moves the agent at index i on to the next state, if it has been in
+    /// its state for long enough
+    fn make_timed_transition(&mut self, i: usize) {
+        let (state, state_for) = (self.agents.states[i], self.agents.state_for[i]);
+        if let Some(state) = self.rules.next_after(state, state_for) {
+            // The action was chosen in the previous state, so it is dropped
+            self.set_state(self.agents.ids[i], state);
+            self.decisions.next_action[i] = None;
+        }
+    }
+
+    /// Synchronous update: all agents decide in parallel on the same state,
+    /// are ticked, then all chosen actions are realised as if one by one in
+    /// the order of the agents, on the grid as it was at the start of the step
+    pub fn perform_actions(&mut self, grid: &Grid, ac: &ActionContext) {
+        self.step += 1;
+        self.activation = 0;
+        self.decide_all(grid, ac);
+        self.tick(grid);
+        self.commit_partitioned(grid, ac);
+    }
+
+    /// The agent's action if it is realised in this step
+    fn ready_action(&self, i: usize) -> Option<usize> {
+        match (
+            self.decisions.next_action[i],
+            self.decisions.remaining_steps[i],
+        ) {
+            (Some(action_ix), 0) => Some(action_ix),
+            _ => None,
+        }
+    }
+
+    /// Realises the chosen actions with the same result as realising them
+    /// one by one in the order of the agents. A movement only changes the
+    /// moving agent, and an effect only reaches the agents within its halo.
+    /// The grid is split into tiles, and the tiles which the halo of some
+    /// effect overlaps are marked. Movements in unmarked tiles can not be
+    /// seen by any effect, so they are committed in parallel. Effects, and
+    /// movements in marked tiles, are realised one by one in agent order
+    fn commit_partitioned(&mut self, grid: &Grid, ac: &ActionContext) {
+        let agent_count: usize = self.agents.ids.len();
+        let tiles_per_side: usize = self.grid_side.div_ceil(TILE_SIDE);
+        let mut marked: Vec<bool> = vec![false; tiles_per_side * tiles_per_side];
+        for i in 0..agent_count {
+            let action_ix = match self.ready_action(i) {
+                Some(action_ix) if !ac.is_movement(action_ix) => action_ix,
+                _ => continue,
+            };
+            let id: Id = self.agents.ids[i];
+            let side: usize = match ac.get_halo(action_ix, id, self) {
+                Some(side) => side,
+                None => {
+                    marked.iter_mut().for_each(|m| *m = true);
+                    break;
+                }
+            };
+            // The halo is widened by the largest body, as agents are found
+            // by any cell of their bodies but tiled by their positions
+            let half: usize = side / 2 + self.max_body_side + 1;
+            let position: Position = self.agents.positions[i];
+            let tiles = |c: usize| {
+                let low: usize = c.saturating_sub(half) / TILE_SIDE;
+                let high: usize = cmp::min(c + half, self.grid_side - 1) / TILE_SIDE;
+                low..=high
+            };
+            for ty in tiles(position.y()) {
+                for tx in tiles(position.x()) {
+                    marked[ty * tiles_per_side + tx] = true;
+                }
+            }
+        }
+        let is_marked = |position: Position| {
+            let (tx, ty) = (position.x() / TILE_SIDE, position.y() / TILE_SIDE);
+            marked[cmp::min(ty, tiles_per_side - 1) * tiles_per_side
+                + cmp::min(tx, tiles_per_side - 1)]
         };
-        agents.par_iter_mut().for_each(|agent| v(agent));
-        self.agents = agents;
-        for i in 0..self.agents.len() {
-            let agent: &mut Agent = &mut self.agents[i];
-            agent.last_un_tagged += 1;
-            if let Some(effect) = agent.next_action {
-                effect(agent.id, self, grid);
+        let (in_order, parallel): (Vec<usize>, Vec<usize>) = (0..agent_count)
+            .filter(|&i| self.ready_action(i).is_some())
+            .partition(|&i| {
+                !ac.is_movement(self.decisions.next_action[i].unwrap())
+                    || is_marked(self.agents.positions[i])
+            });
+
+        let s = &*self;
+        let mut movements: Vec<Option<(usize, Option<Point>)>> = vec![None; agent_count];
+        let destinations: Vec<(usize, usize, Option<Point>)> = parallel
+            .par_iter()
+            .map(|&i| {
+                let action_ix: usize = s.decisions.next_action[i].unwrap();
+                (
+                    i,
+                    action_ix,
+                    ac.roll_destination(action_ix, s.agents.ids[i], s, grid),
+                )
+            })
+            .collect();
+        for (i, action_ix, maybe_destination) in destinations {
+            movements[i] = Some((action_ix, maybe_destination));
+        }
+        let (max_stamina, step, action_count): (f32, usize, usize) =
+            (self.max_stamina, self.step, self.action_count);
+        let agents: &mut Agents = &mut self.agents;
+        let mut changes: Vec<(usize, PositionChange)> = agents
+            .positions
+            .par_iter_mut()
+            .zip(agents.points.par_iter_mut())
+            .zip(
+                agents
+                    .headings
+                    .par_iter_mut()
+                    .zip(agents.speeds.par_iter_mut()),
+            )
+            .zip(agents.stamina.par_iter_mut())
+            .zip(agents.cooldowns.par_chunks_mut(action_count))
+            .zip(agents.ids.par_iter().zip(movements.par_iter()))
+            .enumerate()
+            .filter_map(
+                |(
+                    i,
+                    (
+                        ((((position, point), (heading, speed)), stamina), cooldowns),
+                        (&id, &movement),
+                    ),
+                )| {
+                    let (action_ix, maybe_destination) = movement?;
+                    let (stamina_cost, cooldown) = ac.get_costs(action_ix);
+                    *stamina = (*stamina - stamina_cost).clamp(0.0, max_stamina);
+                    cooldowns[action_ix] = step + cooldown + 1;
+                    move_to(id, maybe_destination, position, point, heading, speed).map(|c| (i, c))
+                },
+            )
+            .collect();
+        changes.extend(self.perform_in_order(grid, ac, &in_order));
+        // Position changes are logged in agent order, as if the actions had
+        // been realised one by one
+        changes.sort_by_key(|&(i, _)| i);
+        self.position_log
+            .extend(changes.into_iter().map(|(_, change)| change));
+    }
+
+    /// Realises the chosen actions of the agents at the indices one by one,
+    /// returning the position changes they made along with the index of the
+    /// agent which made them
+    fn perform_in_order(
+        &mut self,
+        grid: &Grid,
+        ac: &ActionContext,
+        indices: &[usize],
+    ) -> Vec<(usize, PositionChange)> {
+        let earlier: Vec<PositionChange> = self.flush_log();
+        let mut changes: Vec<(usize, PositionChange)> = vec![];
+        for &i in indices {
+            if let Some(action_ix) = self.ready_action(i) {
+                ac.perform(action_ix, self.agents.ids[i], self, grid);
+                changes.extend(self.position_log.drain(..).map(|change| (i, change)));
+            }
+        }
+        self.position_log = earlier;
+        changes
+    }
+
+    /// Sequential update: agents decide and act one at a time, in the given
+    /// order of agent indices, each seeing the actions of those before it.
+    /// Agents may appear in the order any number of times. Like in the
+    /// synchronous update, every agent is ticked once per step, after its
+    /// first decision and before its first action, and agents which do not
+    /// act are ticked at the end of the step
+    pub fn perform_actions_sequentially(
+        &mut self,
+        grid: &mut Grid,
+        ac: &ActionContext,
+        order: &[usize],
+    ) {
+        self.step += 1;
+        let action_count = self.action_count;
+        let mut ticked: Vec<bool> = vec![false; self.agents.ids.len()];
+        for (activation, &i) in order.iter().enumerate() {
+            self.activation = activation;
+            let id: Id = self.agents.ids[i];
+            let mut decisions: Decisions = std::mem::take(&mut self.decisions);
+            let mut rng: StdRng = StdRng::seed_from_u64(self.rng.gen());
+            self.decide(
+                ac,
+                grid,
+                id,
+                &mut decisions.prefs[i * action_count..(i + 1) * action_count],
+                &mut decisions.next_action[i],
+                &mut decisions.remaining_steps[i],
+                &mut rng,
+            );
+            self.decisions = decisions;
+            if !ticked[i] {
+                self.tick_agent(i, grid);
+                ticked[i] = true;
+            }
+            if let (Some(action_ix), 0) = (
+                self.decisions.next_action[i],
+                self.decisions.remaining_steps[i],
+            ) {
+                ac.perform(action_ix, id, self, grid);
+                grid.update(self.flush_log());
             }
         }
+        for i in (0..ticked.len()).filter(|&i| !ticked[i]) {
+            self.tick_agent(i, grid);
+        }
+    }
+
+    /// Ids of all agents
+    pub fn get_ids(&self) -> &[Id] {
+        &self.agents.ids
+    }
+
+    pub fn get_agent_count(&self) -> usize {
+        self.agents.ids.len()
     }
 
     pub fn get_position(&self, id: Id) -> Position {
-        self.get(id).position
+        self.agents.positions[self.agent_ix(id)]
+    }
+
+    pub fn get_point(&self, id: Id) -> Point {
+        self.agents.points[self.agent_ix(id)]
     }
 
-    pub fn set_position(&mut self, id: Id, position: Position) {
-        let before = self.get_mut(id).position;
-        self.position_log.push(PositionChange {
+    pub fn get_heading(&self, id: Id) -> f32 {
+        self.agents.headings[self.agent_ix(id)]
+    }
+
+    /// Displacement of the agent in its last movement
+    pub fn get_velocity(&self, id: Id) -> Point {
+        let index: usize = self.agent_ix(id);
+        let (heading, speed) = (self.agents.headings[index], self.agents.speeds[index]);
+        Point {
+            x: speed * heading.cos(),
+            y: speed * heading.sin(),
+            z: 0.0,
+        }
+    }
+
+    /// Moves the agent to the destination of a movement, or stops it if
+    /// there is none
+    pub fn move_to(&mut self, id: Id, maybe_destination: Option<Point>) {
+        let index: usize = self.agent_ix(id);
+        let agents: &mut Agents = &mut self.agents;
+        if let Some(change) = move_to(
             id,
-            before: before.clone(),
-            after: position.clone(),
-        });
-        self.get_mut(id).position = position;
+            maybe_destination,
+            &mut agents.positions[index],
+            &mut agents.points[index],
+            &mut agents.headings[index],
+            &mut agents.speeds[index],
+        ) {
+            self.position_log.push(change);
+        }
     }
 
-    pub fn get_is_it(&self, id: Id) -> bool {
-        self.get(id).is_it
+    /// Whether the agent's circular body, of diameter `body_side`, fits at
+    /// the point without overlapping the body of any other agent
+    pub fn is_free_for(&self, id: Id, point: Point, grid: &Grid) -> bool {
+        self.is_room_at(point, self.get_attributes(id).body_side, vec![id], grid)
     }
 
-    pub fn set_is_it(&mut self, id: Id, is_it: bool) {
-        self.get_mut(id).is_it = is_it;
+    /// Moves the agent to a random free position in the region, or leaves
+    /// it in place if none was found. Returns whether it was moved. Pending
+    /// position changes are applied to the grid first
+    pub fn relocate(&mut self, id: Id, region: Region, grid: &mut Grid) -> bool {
+        grid.update(self.flush_log());
+        match self.rand_pos(self.get_attributes(id), region, grid) {
+            Some(position) => {
+                self.move_to(id, Some(Point::from(position)));
+                grid.update(self.flush_log());
+                true
+            }
+            None => false,
+        }
     }
 
-    pub fn maybe_get_tagged_by(&self, id: Id) -> Option<Id> {
-        self.get(id).tagged_by
+    /// Whether the agent's type can enter the terrain
+    pub fn can_enter(&self, id: Id, terrain: Terrain) -> bool {
+        self.is_passable(self.get_attributes(id), terrain)
     }
 
-    pub fn set_tagged_by(&mut self, id: Id, tagged_by: Option<Id>) {
-        self.get_mut(id).tagged_by = tagged_by;
+    fn is_passable(&self, attributes: Attributes, terrain: Terrain) -> bool {
+        !self.agent_types[attributes.agent_type]
+            .impassable
+            .contains(&terrain)
+    }
+
+    /// Whether an agent with the given attributes can enter the terrain at
+    /// the position, which must be unblocked and within the boundary, and
+    /// fits there without overlapping any agent but the excluded ones, as a
+    /// square body in discrete space and a circular one in continuous space
+    fn is_room_for(
+        &self,
+        position: Position,
+        attributes: Attributes,
+        excluded_ids: Vec<Id>,
+        grid: &Grid,
+    ) -> bool {
+        if !self.is_passable(attributes, grid.terrain_at(position))
+            || grid.is_blocked(position)
+            || !grid.is_allowed(position)
+        {
+            return false;
+        }
+        let body_side = attributes.body_side;
+        match self.space {
+            Space::Discrete => {
+                grid.is_subgrid_free(position, body_size(body_side), excluded_ids, None)
+            }
+            Space::Continuous => {
+                self.is_room_at(Point::from(position), body_side, excluded_ids, grid)
+            }
+        }
+    }
+
+    /// Whether a circular body of diameter `body_side` fits at the point
+    /// without overlapping the body of any agent, except the excluded ones
+    fn is_room_at(
+        &self,
+        point: Point,
+        body_side: usize,
+        excluded_ids: Vec<Id>,
+        grid: &Grid,
+    ) -> bool {
+        let radius: f32 = body_side as f32 / 2.0;
+        // Bodies which overlap have their centres less than this many cells
+        // apart, in either direction
+        let reach: usize = (body_side + self.max_body_side).div_ceil(2) + 1;
+        let window: usize = 2 * reach + 1;
+        let is_apart = |other: Id| {
+            let other_radius = self.get_attributes(other).body_side as f32 / 2.0;
+            self.get_point(other).distance(point) >= radius + other_radius
+        };
+        grid.is_subgrid_free(
+            point.cell(),
+            body_size(window),
+            excluded_ids,
+            Some(&is_apart),
+        )
+    }
+
+    /// Gap between the circular bodies of the agents in continuous space,
+    /// which is 0 if they touch or overlap
+    pub fn gap_between(&self, id: Id, other: Id) -> f32 {
+        let radii =
+            (self.get_attributes(id).body_side + self.get_attributes(other).body_side) as f32 / 2.0;
+        (self.get_point(id).distance(self.get_point(other)) - radii).max(0.0)
+    }
+
+    /// Side of the square around the agent's cell which holds the cells of
+    /// all agents whose bodies are at most `gap` away from its body, in
+    /// continuous space
+    pub fn gap_window(&self, id: Id, gap: f32) -> usize {
+        let reach: f32 =
+            (self.get_attributes(id).body_side + self.max_body_side) as f32 / 2.0 + gap;
+        2 * (reach.ceil() as usize + 1) + 1
+    }
+
+    pub fn get_stamina(&self, id: Id) -> f32 {
+        self.agents.stamina[self.agent_ix(id)]
+    }
+
+    /// Uses up `amount` of stamina, negative amounts restore it
+    pub fn use_stamina(&mut self, id: Id, amount: f32) {
+        let index: usize = self.agent_ix(id);
+        let stamina: &mut f32 = &mut self.agents.stamina[index];
+        *stamina = (*stamina - amount).clamp(0.0, self.max_stamina);
+    }
+
+    pub fn get_attributes(&self, id: Id) -> Attributes {
+        self.agents.attributes[self.agent_ix(id)]
+    }
+
+    /// Number of cells the agent covers in a sprint
+    pub fn get_speed(&self, id: Id) -> usize {
+        let index: usize = self.agent_ix(id);
+        let speed: usize = self.agents.attributes[index].speed;
+        if self.agents.states[index] == State::It {
+            speed + self.it_speed_bonus
+        } else {
+            speed
+        }
+    }
+
+    /// Number of agents of every type, in the order of `Config::agent_types`
+    pub fn get_type_counts(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: Vec<(&'static str, usize)> =
+            self.agent_types.iter().map(|t| (t.name, 0)).collect();
+        for attributes in &self.agents.attributes {
+            counts[attributes.agent_type].1 += 1;
+        }
+        counts
+    }
+
+    /// Number of steps, including the current one, before the action can be
+    /// chosen again
+    pub fn get_cooldown(&self, id: Id, action_ix: usize) -> usize {
+        self.agents.cooldowns[self.agent_ix(id) * self.action_count + action_ix]
+            .saturating_sub(self.step)
+    }
+
+    /// Keeps the action from being chosen for `cooldown` steps after the
+    /// current one
+    pub fn set_cooldown(&mut self, id: Id, action_ix: usize, cooldown: usize) {
+        let index: usize = self.agent_ix(id);
+        self.agents.cooldowns[index * self.action_count + action_ix] = self.step + cooldown + 1;
+    }
+
+    pub fn get_state(&self, id: Id) -> State {
+        self.agents.states[self.agent_ix(id)]
+    }
+
+    /// Changes the agent's state if the rules have a transition for the
+    /// trigger, returns whether they did
+    pub fn trigger(&mut self, id: Id, trigger: Trigger) -> bool {
+        match self.rules.next(self.get_state(id), trigger) {
+            Some(state) => {
+                self.set_state(id, state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_state(&mut self, id: Id, state: State) {
+        let index: usize = self.agent_ix(id);
+        let before = self.agents.states[index];
+        self.agents.states[index] = state;
+        self.agents.state_for[index] = 0;
+        if before == state {
+            return;
+        }
+        *self.transition_counts.entry((before, state)).or_insert(0) += 1;
+        if before == State::It {
+            self.it_ids.retain(|&it_id| it_id != id);
+        }
+        if state == State::It {
+            self.it_ids.push(id);
+        }
+    }
+
+    /// Number of times agents have changed from one state to the other
+    pub fn get_transition_count(&self, from: State, to: State) -> usize {
+        *self.transition_counts.get(&(from, to)).unwrap_or(&0)
+    }
+
+    /// Number of agents currently in the state
+    pub fn get_state_count(&self, state: State) -> usize {
+        match state {
+            State::It => self.it_ids.len(),
+            _ => self.agents.states.iter().filter(|&&s| s == state).count(),
+        }
+    }
+
+    pub fn get_it_ids(&self) -> &[Id] {
+        &self.it_ids
+    }
+
+    /// How the game has been decided under the rules of the game mode, if
+    /// it has
+    pub fn termination(&self) -> Option<Termination> {
+        let runners = self.get_state_count(State::Runner);
+        match self.game_mode {
+            GameMode::Classic => None,
+            GameMode::Freeze if runners == 0 => Some(Termination::AllFrozen),
+            GameMode::Infection if runners == 0 => Some(Termination::AllInfected),
+            GameMode::Infection if self.it_ids.is_empty() => Some(Termination::NoneInfected),
+            GameMode::Team if self.agents.teams.windows(2).all(|w| w[0] == w[1]) => {
+                Some(Termination::OneTeamLeft)
+            }
+            GameMode::Elimination if runners <= 1 => Some(Termination::LastRunnerStanding),
+            _ => None,
+        }
+    }
+
+    pub fn can_move(&self, id: Id) -> bool {
+        self.rules.can_move(self.get_state(id))
+    }
+
+    /// Whether the agent is allowed to tag anyone at all
+    pub fn can_tag(&self, id: Id) -> bool {
+        self.rules.can_tag(self.get_state(id))
+    }
+
+    /// Whether the agent may tag the target, if it is within reach
+    pub fn can_tag_target(&self, id: Id, target_id: Id) -> bool {
+        self.rules.can_be_tagged(self.get_state(target_id))
+            && (!self.rules.capture || self.get_team(id) != self.get_team(target_id))
+            && self.is_tag_back_allowed(id, target_id)
+    }
+
+    /// Whether the agent is in a safe zone and has not overstayed in it
+    pub fn is_safe(&self, id: Id, grid: &Grid) -> bool {
+        let index: usize = self.agent_ix(id);
+        grid.is_safe(self.agents.positions[index])
+            && !matches!(self.safe_zone_stay_limit, Some(limit) if self.agents.safe_for[index] > limit)
+    }
+
+    /// Moves the agents which have stayed in a safe zone for longer than the
+    /// stay limit to the nearest free cell outside of all safe zones. Agents
+    /// for which there is no such cell stay, unprotected
+    pub fn evict_overstayers(&mut self, grid: &mut Grid) {
+        let limit: usize = match self.safe_zone_stay_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let overstayers: Vec<Id> = (0..self.agents.ids.len())
+            .filter(|&i| self.agents.safe_for[i] > limit)
+            .map(|i| self.agents.ids[i])
+            .collect();
+        for id in overstayers {
+            if let Some(position) = self.nearest_unsafe_room(id, grid) {
+                self.move_to(id, Some(Point::from(position)));
+                grid.update(self.flush_log());
+            }
+        }
+    }
+
+    /// Nearest position outside of all safe zones which the agent fits at,
+    /// searched in growing squares around it, from the first one which
+    /// reaches out of the zone it is in
+    fn nearest_unsafe_room(&self, id: Id, grid: &Grid) -> Option<Position> {
+        let position: Position = self.get_position(id);
+        let attributes: Attributes = self.get_attributes(id);
+        let first_radius: usize = grid.safe_depth(position).max(1);
+        for radius in first_radius as i64..self.grid_side as i64 {
+            for dx in -radius..=radius {
+                // Only the edge of the square is new
+                let dys: Vec<i64> = if dx.abs() == radius {
+                    (-radius..=radius).collect()
+                } else {
+                    vec![-radius, radius]
+                };
+                for dy in dys {
+                    let candidate = match position.shifted(dx, dy, self.grid_side) {
+                        Some(candidate) => candidate,
+                        None => continue,
+                    };
+                    if !grid.is_safe(candidate)
+                        && self.is_room_for(candidate, attributes, vec![id], grid)
+                    {
+                        return Some(candidate);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether the agent is allowed to release anyone at all
+    pub fn can_release(&self, id: Id) -> bool {
+        self.rules.can_release(self.get_state(id))
+    }
+
+    /// Whether the agent may release the target, who must be a teammate
+    pub fn can_release_target(&self, id: Id, target_id: Id) -> bool {
+        self.rules.can_be_released(self.get_state(target_id))
+            && self.get_team(id) == self.get_team(target_id)
+    }
+
+    pub fn get_team(&self, id: Id) -> usize {
+        self.agents.teams[self.agent_ix(id)]
+    }
+
+    /// Moves a tagged agent over to the tagger's team, scoring a point for it
+    pub fn capture(&mut self, id: Id, target_id: Id) {
+        let team = self.get_team(id);
+        let index: usize = self.agent_ix(target_id);
+        self.agents.teams[index] = team;
+        self.team_scores[team] += 1;
+    }
+
+    /// Number of captures and current size of every team
+    pub fn get_team_stats(&self) -> Vec<(usize, usize)> {
+        let mut sizes: Vec<usize> = vec![0; self.team_scores.len()];
+        for &team in &self.agents.teams {
+            sizes[team] += 1;
+        }
+        self.team_scores.iter().copied().zip(sizes).collect()
+    }
+
+    /// Number of susceptible (runners), infected ('it') and recovered agents
+    pub fn get_sir_counts(&self) -> (usize, usize, usize) {
+        (
+            self.get_state_count(State::Runner),
+            self.get_state_count(State::It),
+            self.get_state_count(State::Recovered),
+        )
+    }
+
+    /// Remembers that the target was tagged by the agent, forgetting the
+    /// oldest tagger if the history is full
+    pub fn record_tag(&mut self, id: Id, target_id: Id) {
+        let step = self.step;
+        let history_length = self.tag_back_rules.history_length;
+        let index: usize = self.agent_ix(target_id);
+        let tagged_by = &mut self.agents.tagged_by[index];
+        tagged_by.push_front((id, step));
+        tagged_by.truncate(history_length);
+    }
+
+    /// Whether the tag-back rules allow the agent to tag the target
+    fn is_tag_back_allowed(&self, id: Id, target_id: Id) -> bool {
+        let rules = self.tag_back_rules;
+        let within = |step: usize, window: usize| self.step < step.saturating_add(window);
+        let target_tagged_by = &self.agents.tagged_by[self.agent_ix(target_id)];
+        let is_immune = target_tagged_by
+            .front()
+            .is_some_and(|&(_, step)| within(step, rules.immunity_steps));
+        let is_retag = target_tagged_by
+            .iter()
+            .any(|&(tagger, step)| tagger == id && within(step, rules.retag_window));
+        let is_tag_back = self.agents.tagged_by[self.agent_ix(id)]
+            .iter()
+            .any(|&(tagger, step)| tagger == target_id && within(step, rules.no_tag_back_steps));
+        !is_immune && !is_retag && !is_tag_back
     }
 
     pub fn get_tagged_count(&self) -> usize {
@@ -175,7 +1160,8 @@ impl AgentManager {
     }
 
     pub fn reset_last_untagged(&mut self, id: Id) {
-        self.get_mut(id).last_un_tagged = 0;
+        let index: usize = self.agent_ix(id);
+        self.agents.last_un_tagged[index] = 0;
     }
 
     pub fn flush_log(&mut self) -> Vec<PositionChange> {
@@ -183,75 +1169,505 @@ impl AgentManager {
     }
 
     pub fn get_render_info(&mut self) -> Vec<RenderObject> {
+        let agents: &Agents = &self.agents;
         let mut v: Vec<RenderObject> = vec![];
-        for agent in &self.agents {
-            v.push((agent.position, agent.is_it, agent.last_un_tagged));
+        for i in 0..agents.ids.len() {
+            v.push(RenderObject {
+                position: agents.points[i],
+                state: agents.states[i],
+                last_un_tagged: agents.last_un_tagged[i],
+                team: match self.game_mode {
+                    GameMode::Team => Some(agents.teams[i]),
+                    _ => None,
+                },
+                body_side: agents.attributes[i].body_side,
+            });
         }
         v
     }
 
-    fn rand_pos(&mut self, grid: &mut Grid) -> Option<Position> {
-        let mut rand_pos: Position = Position::random();
+    fn rand_attributes(&mut self) -> Attributes {
+        let agent_type: usize = self.agent_type_dist.sample(&mut self.rng);
+        let t: &AgentType = &self.agent_types[agent_type];
+        let (body_side, tag_reach, speed, vision_radius) =
+            (t.body_side, t.tag_reach, t.speed, t.vision_radius);
+        let rng = &mut self.rng;
+        let mut draw = |(low, high): (usize, usize)| rng.gen_range(low, high + 1);
+        Attributes {
+            agent_type,
+            body_side: draw(body_side),
+            tag_reach: draw(tag_reach),
+            speed: draw(speed),
+            vision_radius: draw(vision_radius),
+        }
+    }
+
+    fn rand_pos(
+        &mut self,
+        attributes: Attributes,
+        region: Region,
+        grid: &mut Grid,
+    ) -> Option<Position> {
+        let levels: usize = grid.levels();
+        let mut rand_pos: Position = self.rand_pos_on_any_level(region, levels)?;
         let mut c: usize = 0;
-        while !grid.is_subgrid_free(rand_pos, STEP_SG_SIDE, STEP_SG_SIDE, vec![], None) {
+        while !self.is_room_for(rand_pos, attributes, vec![], grid) {
             if c > 500 {
                 return None;
             }
-            rand_pos = Position::random();
+            rand_pos = self.rand_pos_on_any_level(region, levels)?;
             c += 1;
         }
         Some(rand_pos)
     }
 
-    fn new_id(&mut self) -> Id {
-        let rng = &mut self.rng;
-        let mut id: Id = rng.gen_range(1, Id::max_value());
-        while self.id_map.contains_key(&id) {
-            id = rng.gen_range(1, Id::max_value());
+    /// Random number generator of the agent at the current step, which
+    /// follows from the manager's seed. Unlike `rng` it does not depend on
+    /// what other agents have drawn, so agents can use it in any order
+    pub fn agent_rng(&self, id: Id) -> StdRng {
+        StdRng::seed_from_u64(hash_seed(&[
+            self.seed,
+            self.step as u64,
+            self.activation as u64,
+            id.as_u64(),
+        ]))
+    }
+
+    /// Random number generator for the agent's decision at the current
+    /// step, which is independent of `agent_rng`
+    fn decision_rng(&self, id: Id) -> StdRng {
+        // The stream number keeps the seed apart from that of `agent_rng`
+        let stream: u64 = 1;
+        StdRng::seed_from_u64(hash_seed(&[
+            self.seed,
+            self.step as u64,
+            id.as_u64(),
+            stream,
+        ]))
+    }
+
+    /// Random position in the region, which spans all levels, or None if the
+    /// region does not intersect the grid
+    fn rand_pos_on_any_level(&mut self, region: Region, levels: usize) -> Option<Position> {
+        let position: Position = region.random_position(self.grid_side, &mut self.rng)?;
+        if levels > 1 {
+            Some(Position::new(
+                position.x(),
+                position.y(),
+                self.rng.gen_range(0, levels),
+            ))
+        } else {
+            Some(position)
         }
-        id
     }
 
-    fn get_mut(&mut self, id: Id) -> &mut Agent {
-        let index: usize = *self.id_map.get(&id).unwrap();
-        &mut self.agents[index]
+    /// Whether the id belongs to an agent which has not been removed
+    pub fn contains(&self, id: Id) -> bool {
+        self.slots
+            .get(id.index as usize)
+            .is_some_and(|slot| slot.generation == id.generation && slot.agent_ix.is_some())
     }
 
-    fn get(&self, id: Id) -> &Agent {
-        let index: usize = *self.id_map.get(&id).unwrap();
-        &self.agents[index]
+    /// Takes a free slot, or a new one if there are none
+    fn new_id(&mut self) -> Id {
+        match self.free_slots.pop() {
+            Some(index) => Id {
+                index,
+                generation: self.slots[index as usize].generation,
+            },
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    agent_ix: None,
+                });
+                Id {
+                    index: (self.slots.len() - 1) as u32,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    fn agent_ix(&self, id: Id) -> usize {
+        let slot: &Slot = &self.slots[id.index as usize];
+        match slot.agent_ix {
+            Some(index) if slot.generation == id.generation => index,
+            _ => panic!("agent {:?} has been removed", id),
+        }
     }
 
     fn update_preference(
-        agent: &mut Agent,
-        mean_preferences: &Vec<f32>,
+        pref: &mut [f32],
+        mean_preferences: &[f32],
         action_count: usize,
-        rng: &mut rand::prelude::ThreadRng,
+        rng: &mut StdRng,
     ) {
         let mut rand_ix: usize = rng.gen_range(0, action_count);
         let rm: f32 = 1.5;
         let rand_val: f32 = rng.gen_range(1.0 / rm, rm);
-        agent.pref[rand_ix] *= rand_val;
+        pref[rand_ix] *= rand_val;
 
         rand_ix = rng.gen_range(0, action_count);
         if rng.gen::<f32>() < 0.02 * mean_preferences[rand_ix] {
-            agent.pref[rand_ix] = mean_preferences[rand_ix];
+            pref[rand_ix] = mean_preferences[rand_ix];
         }
     }
 
-    fn get_actions_ordering(agent: &mut Agent, rng: &mut rand::prelude::ThreadRng) -> Vec<usize> {
-        let pref: &Vec<f32> = &agent.pref;
-        let mut vals: Vec<f32> = pref.clone();
+    fn get_actions_ordering(pref: &[f32], rng: &mut StdRng) -> Vec<usize> {
+        let mut vals: Vec<f32> = pref.to_vec();
         // let min: f32 = vals.iter().fold(f64::INFINITY, |a, &b| a.min(b));
-        for i in 0..vals.len() {
-            vals[i] *= rng.gen::<f32>();
+        for val in vals.iter_mut() {
+            *val *= rng.gen::<f32>();
         }
-        let mut ordering: Vec<(usize, &f32)> = (0 as usize..).zip(vals.iter()).collect();
+        let mut ordering: Vec<(usize, &f32)> = (0_usize..).zip(vals.iter()).collect();
         ordering.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
-        let ix: Vec<usize> = ordering
-            .iter()
-            .map(|(i, _)| i.clone())
-            .collect::<Vec<usize>>();
+        let ix: Vec<usize> = ordering.iter().map(|(i, _)| *i).collect::<Vec<usize>>();
         ix
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Schedule, TerrainEffect, TerrainEffects, TerrainPatch};
+    use crate::TERRAIN_EFFECTS;
+    use std::sync::{Arc, Mutex};
+
+    fn config(num_agents: usize) -> Config {
+        Config {
+            num_agents,
+            num_agents_it: 0,
+            ..Config::default()
+        }
+    }
+
+    fn ids(am: &AgentManager) -> Vec<Id> {
+        am.agents.ids.clone()
+    }
+
+    #[test]
+    fn overstayers_are_moved_out_of_safe_zones() {
+        let config = Config {
+            safe_zones: vec![Region {
+                x: (0.0, 0.5),
+                y: (0.0, 1.0),
+            }],
+            safe_zone_stay_limit: Some(2),
+            ..config(1)
+        };
+        let ac = ActionContext::new(&config);
+        let mut grid = Grid::new(&config);
+        let mut am = AgentManager::new(&config, &ac, &mut grid);
+        let id = am.agents.ids[0];
+        am.move_to(id, Some(Point::from(Position::new(499, 500, 0))));
+        grid.update(am.flush_log());
+        am.agents.safe_for[0] = 2;
+        am.evict_overstayers(&mut grid);
+        assert_eq!(am.get_position(id), Position::new(499, 500, 0));
+        am.agents.safe_for[0] = 3;
+        am.evict_overstayers(&mut grid);
+        let position = am.get_position(id);
+        assert_eq!(position.x(), 500);
+        assert!(!grid.is_safe(position));
+        assert!(grid.cell(position).contains(&id));
+    }
+
+    #[test]
+    fn tag_back_rules_expire_and_forget_old_taggers() {
+        let config = Config {
+            tag_back_rules: TagBackRules {
+                no_tag_back_steps: 3,
+                retag_window: 10,
+                immunity_steps: 2,
+                history_length: 1,
+            },
+            ..config(3)
+        };
+        let ac = ActionContext::new(&config);
+        let mut grid = Grid::new(&config);
+        let mut am = AgentManager::new(&config, &ac, &mut grid);
+        let (a, b, c) = (ids(&am)[0], ids(&am)[1], ids(&am)[2]);
+        am.step = 10;
+        am.record_tag(a, b);
+        // Nobody may tag b while it is immune, nor b tag a back
+        assert!(!am.is_tag_back_allowed(c, b));
+        assert!(!am.is_tag_back_allowed(b, a));
+        assert!(am.is_tag_back_allowed(b, c));
+        am.step = 12;
+        assert!(am.is_tag_back_allowed(c, b));
+        assert!(!am.is_tag_back_allowed(a, b));
+        assert!(!am.is_tag_back_allowed(b, a));
+        am.step = 13;
+        assert!(am.is_tag_back_allowed(b, a));
+        assert!(!am.is_tag_back_allowed(a, b));
+        // b only remembers its latest tagger, so a may tag it again even
+        // though its retag window has not run out
+        am.record_tag(c, b);
+        am.step = 15;
+        assert!(am.is_tag_back_allowed(a, b));
+        assert!(!am.is_tag_back_allowed(c, b));
+        // Windows of 0 turn the rules off
+        am.tag_back_rules.retag_window = 0;
+        assert!(am.is_tag_back_allowed(c, b));
+    }
+
+    #[test]
+    fn stale_ids_are_rejected_after_their_slot_is_reused() {
+        let config = config(3);
+        let ac = ActionContext::new(&config);
+        let mut grid = Grid::new(&config);
+        let mut am = AgentManager::new(&config, &ac, &mut grid);
+        let (a, b, c) = (ids(&am)[0], ids(&am)[1], ids(&am)[2]);
+        assert!(am.remove_agent(b, &mut grid));
+        assert!(!am.contains(b));
+        // The new agent takes over b's slot, under a new generation
+        let d = am
+            .add_agent(State::Runner, 0, Region::WHOLE, None, &mut grid)
+            .unwrap();
+        assert_eq!(d.index, b.index);
+        assert_ne!(d, b);
+        assert!(!am.contains(b) && am.contains(d));
+        assert!(!am.remove_agent(b, &mut grid));
+        assert!(am.contains(d));
+        // c was moved into b's place in the buffers and is still found
+        assert_eq!(ids(&am), &[a, c, d]);
+        for id in [a, c, d] {
+            let position = am.get_position(id);
+            assert!(grid.cell(position).contains(&id));
+        }
+    }
+
+    #[test]
+    fn partitioned_commit_matches_acting_in_order() {
+        let config = Config {
+            num_agents: 3000,
+            num_agents_it: 60,
+            it_speed_bonus: 1,
+            agent_types: vec![AgentType {
+                name: "mixed",
+                share: 1.0,
+                body_side: (1, 3),
+                tag_reach: (3, 9),
+                speed: (1, 3),
+                vision_radius: (2, 8),
+                impassable: &[],
+            }],
+            ..config(0)
+        };
+        let ac = ActionContext::new(&config);
+        let (mut grid, mut reference_grid) = (Grid::new(&config), Grid::new(&config));
+        let mut am = AgentManager::with_seed(&config, &ac, &mut grid, 7);
+        let mut reference = AgentManager::with_seed(&config, &ac, &mut reference_grid, 7);
+        for _ in 0..60 {
+            am.perform_actions(&grid, &ac);
+            grid.update(am.flush_log());
+
+            // The same step with every action realised one by one
+            reference.step += 1;
+            reference.decide_all(&reference_grid, &ac);
+            reference.tick(&reference_grid);
+            let indices: Vec<usize> = (0..reference.agents.ids.len()).collect();
+            let changes = reference.perform_in_order(&reference_grid, &ac, &indices);
+            reference
+                .position_log
+                .extend(changes.into_iter().map(|(_, change)| change));
+            reference_grid.update(reference.flush_log());
+
+            let (a, b) = (&am.agents, &reference.agents);
+            assert!(a.positions == b.positions);
+            assert_eq!(a.points, b.points);
+            assert_eq!(a.states, b.states);
+            assert_eq!(a.stamina, b.stamina);
+            assert_eq!(a.cooldowns, b.cooldowns);
+            assert_eq!(a.tagged_by, b.tagged_by);
+            assert_eq!(a.last_un_tagged, b.last_un_tagged);
+            assert_eq!(am.it_ids, reference.it_ids);
+            assert_eq!(am.tagged_count, reference.tagged_count);
+            assert!(grid.val == reference_grid.val);
+        }
+        assert!(am.tagged_count > 0);
+    }
+
+    #[test]
+    fn agent_rolls_are_fixed_and_differ_between_activations() {
+        // First output of SplitMix64 seeded with 0
+        assert_eq!(hash_seed(&[0]), 0xe220_a839_7b1d_cdaf);
+        let config = config(1);
+        let ac = ActionContext::new(&config);
+        let mut grid = Grid::new(&config);
+        let mut am = AgentManager::with_seed(&config, &ac, &mut grid, 3);
+        let id = am.get_ids()[0];
+        let first: u64 = am.agent_rng(id).gen();
+        assert_eq!(first, am.agent_rng(id).gen::<u64>());
+        am.activation = 1;
+        assert_ne!(first, am.agent_rng(id).gen::<u64>());
+    }
+
+    /// Steps at which a single agent realises the effect of its only action,
+    /// which is always allowed apart from its cooldown. Sequential
+    /// schedules activate the agent as often as `activations` says at every
+    /// step
+    fn effect_steps(
+        cooldown: usize,
+        duration: usize,
+        schedule: Schedule,
+        activations: impl Fn(usize) -> usize,
+    ) -> Vec<usize> {
+        let log: Arc<Mutex<Vec<usize>>> = Arc::new(Mutex::new(vec![]));
+        let effect_log = Arc::clone(&log);
+        let action = Action {
+            cooldown,
+            duration,
+            ..Action::new(
+                Box::new(|_, _, _| true),
+                Box::new(move |_, am, _| effect_log.lock().unwrap().push(am.step)),
+            )
+        };
+        let ac = ActionContext::from_actions(vec![action], vec![1.0]);
+        let config = config(1);
+        let mut grid = Grid::new(&config);
+        let mut am = AgentManager::new(&config, &ac, &mut grid);
+        for step in 1..=20 {
+            match schedule {
+                Schedule::Synchronous => am.perform_actions(&grid, &ac),
+                _ => am.perform_actions_sequentially(&mut grid, &ac, &vec![0; activations(step)]),
+            }
+        }
+        let steps = log.lock().unwrap().clone();
+        steps
+    }
+
+    #[test]
+    fn schedules_agree_on_cooldowns_and_durations() {
+        for &(cooldown, duration) in &[(0, 1), (3, 1), (0, 3), (3, 2)] {
+            // Effects are realised on the last step of the action, and the
+            // action is chosen again right after its cooldown has run out
+            let expected: Vec<usize> = (duration..=20).step_by(duration + cooldown).collect();
+            for &schedule in &[
+                Schedule::Synchronous,
+                Schedule::RandomSequential,
+                Schedule::PoissonAsynchronous,
+            ] {
+                let steps = effect_steps(cooldown, duration, schedule, |_| 1);
+                assert_eq!(
+                    steps, expected,
+                    "{:?} with cooldown {} and duration {}",
+                    schedule, cooldown, duration
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cooldowns_count_steps_not_activations() {
+        let synchronous = effect_steps(3, 1, Schedule::Synchronous, |_| 1);
+        assert_eq!(synchronous, vec![1, 5, 9, 13, 17]);
+        // Acting twice a step, or not at all every other step
+        assert_eq!(
+            effect_steps(3, 1, Schedule::PoissonAsynchronous, |_| 2),
+            synchronous
+        );
+        assert_eq!(
+            effect_steps(3, 1, Schedule::PoissonAsynchronous, |step| step % 2),
+            synchronous
+        );
+    }
+
+    #[test]
+    fn durations_count_activations() {
+        // A two-step action is realised at the second activation
+        assert_eq!(
+            effect_steps(0, 2, Schedule::PoissonAsynchronous, |_| 2),
+            (1..=20).collect::<Vec<usize>>()
+        );
+        assert_eq!(
+            effect_steps(0, 2, Schedule::PoissonAsynchronous, |step| step % 2),
+            vec![3, 7, 11, 15, 19]
+        );
+    }
+
+    #[test]
+    fn continuous_tag_reach_is_measured_between_bodies() {
+        let config = Config {
+            grid_side: 40,
+            space: Space::Continuous,
+            collision_detection: true,
+            safe_zones: vec![],
+            agent_types: vec![AgentType {
+                name: "disc",
+                share: 1.0,
+                body_side: (9, 9),
+                tag_reach: (4, 4),
+                speed: (1, 1),
+                vision_radius: (1, 1),
+                impassable: &[],
+            }],
+            ..config(0)
+        };
+        let ac = ActionContext::new(&config);
+        let tag_ix: usize = (0..ac.action_count)
+            .find(|&ix| !ac.is_movement(ix))
+            .unwrap();
+        // Bodies which touch, are one cell apart, and three cells apart
+        for &(x, gap, can_tag) in &[(19, 0.0, true), (20, 1.0, true), (22, 3.0, false)] {
+            let mut grid = Grid::new(&config);
+            let mut am = AgentManager::with_seed(&config, &ac, &mut grid, 0);
+            let position = |x: usize| Some(Position::new(x, 10, 0));
+            let it: Id = am
+                .add_agent(State::It, 0, Region::WHOLE, position(10), &mut grid)
+                .unwrap();
+            let runner: Id = am
+                .add_agent(State::Runner, 0, Region::WHOLE, position(x), &mut grid)
+                .unwrap();
+            assert_eq!(am.gap_between(it, runner), gap);
+            assert_eq!(
+                ac.is_allowed(tag_ix, it, &am, &grid),
+                can_tag,
+                "gap of {}",
+                gap
+            );
+        }
+    }
+
+    #[test]
+    fn failed_terrain_rolls_still_spend_the_move() {
+        for &move_probability in &[0.0, 1.0] {
+            let water = TerrainEffect {
+                move_probability,
+                speed_factor: 1.0,
+            };
+            let config = Config {
+                terrain: vec![TerrainPatch {
+                    terrain: Terrain::Water,
+                    region: Region::WHOLE,
+                }],
+                terrain_effects: TerrainEffects {
+                    water,
+                    ..TERRAIN_EFFECTS
+                },
+                step_stamina_cost: 1.0,
+                ..config(1)
+            };
+            // Stepping left or right, one of which is always possible
+            let steps: Vec<Action> = vec![
+                ActionContext::step(-1, 0, &config),
+                ActionContext::step(1, 0, &config),
+            ]
+            .into_iter()
+            .map(|step| Action {
+                cooldown: 5,
+                ..step
+            })
+            .collect();
+            let ac = ActionContext::from_actions(steps, vec![1.0, 1.0]);
+            let mut grid = Grid::new(&config);
+            let mut am = AgentManager::new(&config, &ac, &mut grid);
+            let id: Id = am.get_ids()[0];
+            let position: Position = am.get_position(id);
+            am.perform_actions(&grid, &ac);
+            assert_eq!(am.get_position(id) == position, move_probability == 0.0);
+            assert_eq!(am.get_stamina(id), config.max_stamina - 1.0);
+            assert!((0..2).any(|action_ix| am.get_cooldown(id, action_ix) > 0));
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use crate::{
+    AGENT_TYPES, BOUNDARY, COLLSION_DETECTION, DOORS, GAME_MODE, GRID_LEVELS, GRID_SIDE,
+    INFECTION_CURVE_PATH, INFECTION_RECOVERY_STEPS, IT_SPEED_BONUS, MAX_STAMINA, NUM_AGENTS,
+    NUM_AGENTS_IT, NUM_ROUNDS, NUM_STEPS, OBSTACLES, OUTSIDE_RULE, REST_STAMINA_GAIN, SAFE_ZONES,
+    SAFE_ZONE_STAY_LIMIT, SCHEDULE, SEED, SPACE, SPAWN_POINTS, SPRINT_DURATION,
+    SPRINT_STAMINA_COST, STEER_ANGLE, STEP_STAMINA_COST, TAG_BACK_RULES, TAG_COOLDOWN, TEAMS,
+    TERRAIN, TERRAIN_EFFECTS, TOPOLOGY, VALIDATE, VIEW_LEVEL,
+};
+
+use crate::grid::Region;
+use crate::state::Rules;
+
+/// Rules of the game being played
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GameMode {
+    /// Tagging someone passes on being 'it'
+    Classic,
+    /// Tagged agents are frozen in place until a runner touches them, the
+    /// taggers stay 'it' and the game ends when all runners are frozen
+    Freeze,
+    /// Tagged agents become 'it' as well, spreading like an infection. If a
+    /// recovery time is set, agents stop being 'it' after it and can not be
+    /// tagged again. The game ends when no one is left to infect, or no one
+    /// is infected anymore
+    Infection,
+    /// Every agent belongs to a team and can tag members of other teams,
+    /// who are captured and join the tagger's team. Every capture scores a
+    /// point for the tagger's team, and the game ends when one team is left
+    Team,
+    /// Tagged runners leave the field, and the game ends when at most one
+    /// runner is left
+    Elimination,
+}
+
+/// Order in which agents decide and act within a step
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Schedule {
+    /// All agents decide on the same state, then all actions are realised
+    Synchronous,
+    /// Agents decide and act one at a time, in a new random order every
+    /// step, each seeing the actions of those before it
+    RandomSequential,
+    /// Every agent acts at the ticks of its own Poisson clock with a rate of
+    /// one per step, so it may act several times in a step or not at all.
+    /// Agents act one at a time, in the order of their ticks
+    PoissonAsynchronous,
+}
+
+/// Space agents move in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Space {
+    /// Agents are on grid cells and step to neighbouring cells
+    Discrete,
+    /// Agents have real-valued positions, a heading and a velocity, and
+    /// steer by turning. Bodies are circles, and the grid is only used to
+    /// find agents near a point
+    Continuous,
+}
+
+/// Which cells of a discrete grid neighbour each other, i.e. where agents
+/// can step to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Topology {
+    /// Square cells, stepping left, right, up or down
+    VonNeumann,
+    /// Square cells, stepping diagonally as well
+    Moore,
+    /// Hexagonal cells in axial coordinates, where the six neighbours of
+    /// (x, y) are (x ± 1, y), (x, y ± 1), (x + 1, y - 1) and (x - 1, y + 1)
+    Hex,
+}
+
+impl Topology {
+    /// Offsets of the neighbours of a cell
+    pub fn directions(&self) -> &'static [(i64, i64)] {
+        match self {
+            Topology::VonNeumann => &[(-1, 0), (1, 0), (0, -1), (0, 1)],
+            Topology::Moore => &[
+                (-1, 0),
+                (1, 0),
+                (0, -1),
+                (0, 1),
+                (-1, -1),
+                (1, -1),
+                (-1, 1),
+                (1, 1),
+            ],
+            Topology::Hex => &[(-1, 0), (1, 0), (0, -1), (0, 1), (1, -1), (-1, 1)],
+        }
+    }
+}
+
+/// Ground covering a cell, which can slow agents down or keep them out
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Terrain {
+    Grass,
+    Sand,
+    Mud,
+    Water,
+}
+
+/// Area of the map covered by a terrain
+#[derive(Clone, Copy)]
+pub struct TerrainPatch {
+    pub terrain: Terrain,
+    pub region: Region,
+}
+
+/// How a terrain affects agents moving over it
+#[derive(Clone, Copy)]
+pub struct TerrainEffect {
+    /// Chance that an agent trying to move onto the terrain manages to
+    pub move_probability: f32,
+    /// Factor by which the distance of sprints starting on the terrain is
+    /// scaled, they always cover at least one cell
+    pub speed_factor: f32,
+}
+
+/// Effects of every terrain on movement
+#[derive(Clone, Copy)]
+pub struct TerrainEffects {
+    pub grass: TerrainEffect,
+    pub sand: TerrainEffect,
+    pub mud: TerrainEffect,
+    pub water: TerrainEffect,
+}
+
+impl TerrainEffects {
+    pub fn of(&self, terrain: Terrain) -> TerrainEffect {
+        match terrain {
+            Terrain::Grass => self.grass,
+            Terrain::Sand => self.sand,
+            Terrain::Mud => self.mud,
+            Terrain::Water => self.water,
+        }
+    }
+}
+
+/// Rectangle agents can not move onto, which moves back and forth along a
+/// path. Agents it moves over are not harmed and can step off it
+#[derive(Clone, Copy)]
+pub struct Obstacle {
+    /// Area covered at the start of the path
+    pub region: Region,
+    /// Offsets from the start, in fractions of the grid side, the obstacle
+    /// moves through in turn before returning to the start
+    pub path: &'static [(f32, f32)],
+    /// Number of steps it takes to move from one point of the path to the next
+    pub steps_per_leg: usize,
+}
+
+impl Obstacle {
+    /// Area covered after the given number of steps of a round
+    pub fn region_at(&self, step: usize) -> Region {
+        let leg_count: usize = self.path.len() + 1;
+        let point = |leg: usize| match leg % leg_count {
+            0 => (0.0, 0.0),
+            leg => self.path[leg - 1],
+        };
+        let steps_per_leg: usize = self.steps_per_leg.max(1);
+        let leg: usize = step / steps_per_leg;
+        let t: f32 = (step % steps_per_leg) as f32 / steps_per_leg as f32;
+        let ((x_from, y_from), (x_to, y_to)) = (point(leg), point(leg + 1));
+        self.region
+            .shifted(x_from + (x_to - x_from) * t, y_from + (y_to - y_from) * t)
+    }
+}
+
+/// Area agents can not move onto while it is closed. Doors start open and
+/// then keep opening and closing
+#[derive(Clone, Copy)]
+pub struct Door {
+    pub region: Region,
+    /// Number of steps the door stays open
+    pub open_steps: usize,
+    /// Number of steps the door stays closed
+    pub closed_steps: usize,
+}
+
+impl Door {
+    /// Whether the door is open after the given number of steps of a round
+    pub fn is_open_at(&self, step: usize) -> bool {
+        step % (self.open_steps + self.closed_steps).max(1) < self.open_steps
+    }
+}
+
+/// Boundary of the area agents are allowed in, which shrinks from the whole
+/// grid to a final region, like in a battle royale
+#[derive(Clone, Copy)]
+pub struct Boundary {
+    /// Step of the round at which the boundary starts shrinking
+    pub start_step: usize,
+    /// Step of the round at which the boundary reaches the final region
+    pub end_step: usize,
+    pub final_region: Region,
+}
+
+impl Boundary {
+    /// Area agents are allowed in after the given number of steps of a round
+    pub fn region_at(&self, step: usize) -> Region {
+        let duration: usize = self.end_step.saturating_sub(self.start_step).max(1);
+        let t: f32 = (step.saturating_sub(self.start_step) as f32 / duration as f32).min(1.0);
+        let (whole, last) = (Region::WHOLE, self.final_region);
+        Region {
+            x: (
+                whole.x.0 + (last.x.0 - whole.x.0) * t,
+                whole.x.1 + (last.x.1 - whole.x.1) * t,
+            ),
+            y: (
+                whole.y.0 + (last.y.0 - whole.y.0) * t,
+                whole.y.1 + (last.y.1 - whole.y.1) * t,
+            ),
+        }
+    }
+}
+
+/// What happens to agents outside the boundary after it has shrunk
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutsideRule {
+    /// They leave the game
+    Eliminate,
+    /// They are moved to a random free cell within the boundary
+    Teleport,
+    /// They lose the given amount of stamina every step until they are back
+    /// within the boundary. Moves towards it need no stamina, so that they
+    /// can always head back in
+    Drain(f32),
+}
+
+/// A team in team tag
+#[derive(Clone)]
+pub struct Team {
+    pub name: &'static str,
+    /// Number of agents the team starts with
+    pub size: usize,
+    /// Area in which the team's agents start
+    pub region: Region,
+}
+
+/// Restrictions on who can be tagged, based on who tagged whom recently.
+/// Windows are given in steps, and a window of 0 turns the rule off
+#[derive(Clone, Copy)]
+pub struct TagBackRules {
+    /// Steps after being tagged during which an agent can not tag back the
+    /// agent who tagged it
+    pub no_tag_back_steps: usize,
+    /// Steps after tagging someone during which an agent can not tag the
+    /// same agent again
+    pub retag_window: usize,
+    /// Steps after being tagged during which an agent can not be tagged
+    pub immunity_steps: usize,
+    /// Number of recent taggers every agent remembers, older tags are
+    /// forgotten even if they are still within a window
+    pub history_length: usize,
+}
+
+/// Area in which new runners appear during a round
+#[derive(Clone, Copy)]
+pub struct SpawnPoint {
+    pub region: Region,
+    /// Number of steps between two new runners
+    pub interval: usize,
+    /// Index of the team new runners join in `Config::teams`, 0 unless
+    /// playing team tag
+    pub team: usize,
+}
+
+/// A named kind of agent. Every agent of this type gets its attributes
+/// drawn uniformly from the given inclusive ranges
+#[derive(Clone)]
+pub struct AgentType {
+    pub name: &'static str,
+    /// Share of the population of this type, relative to the other types
+    pub share: f32,
+    /// Side length of the square which must be free around a step destination
+    pub body_side: (usize, usize),
+    /// Side length of the square around the agent in which it can tag others.
+    /// In continuous space, others can be tagged whose bodies are at most
+    /// half of it away from the agent's body
+    pub tag_reach: (usize, usize),
+    /// Number of cells covered by a sprint
+    pub speed: (usize, usize),
+    /// Distance up to which the agent notices others, it only sprints if an
+    /// agent it chases or flees from is this close. In continuous space it
+    /// is measured between the edges of their bodies
+    pub vision_radius: (usize, usize),
+    /// Terrains agents of this type can not enter
+    pub impassable: &'static [Terrain],
+}
+
+/// Runtime parameters of a simulation. The defaults are taken from the
+/// constants in lib.rs, but a config can be built and modified freely,
+/// e.g. to construct actions with different step or tag distances
+#[derive(Clone)]
+pub struct Config {
+    /// Rules of the game being played
+    pub game_mode: GameMode,
+    /// Order in which agents decide and act within a step
+    pub schedule: Schedule,
+    /// Space agents move in
+    pub space: Space,
+    /// In discrete space, which cells neighbour each other
+    pub topology: Topology,
+    /// In continuous space, angle in degrees by which agents turn when
+    /// steering left or right
+    pub steer_angle: f32,
+    /// Number of rounds played, every round starts from a new population
+    pub num_rounds: usize,
+    /// Maximum number of steps in a round
+    pub num_steps: usize,
+    /// Number of agents, unless playing team tag
+    pub num_agents: usize,
+    /// Number of agents which are initially 'it', unless playing team tag
+    pub num_agents_it: usize,
+    /// Areas in which new runners appear during a round
+    pub spawn_points: Vec<SpawnPoint>,
+    /// Side length of the square grid
+    pub grid_side: usize,
+    /// Number of levels stacked in the grid, 1 for a flat world. Agents
+    /// climb up and down between levels
+    pub levels: usize,
+    /// Level shown by the viewer, or None to project all levels onto the
+    /// screen with higher levels drawn on top
+    pub view_level: Option<usize>,
+    /// Whether agents act as movement barriers to each other
+    pub collision_detection: bool,
+    /// Whether the grid and agents are checked for consistency after every step
+    pub validate: bool,
+    /// Seed of the random numbers the agents draw, or None for a random seed
+    pub seed: Option<u64>,
+    /// States of agents and transitions between them, by default those of
+    /// the game mode
+    pub rules: Rules,
+    /// In infection mode, file to which the number of susceptible, infected
+    /// and recovered agents at every step is written, if at all
+    pub infection_curve_path: Option<&'static str>,
+    /// In team mode, the teams playing
+    pub teams: Vec<Team>,
+    /// Areas in which agents can not be tagged
+    pub safe_zones: Vec<Region>,
+    /// Areas not covered by grass, later patches lying on top of earlier ones
+    pub terrain: Vec<TerrainPatch>,
+    /// Effects of every terrain on movement
+    pub terrain_effects: TerrainEffects,
+    /// Moving rectangles agents can not move onto
+    pub obstacles: Vec<Obstacle>,
+    /// Areas agents can not move onto while they are closed
+    pub doors: Vec<Door>,
+    /// Shrinking boundary of the area agents are allowed in, if any
+    pub boundary: Option<Boundary>,
+    /// What happens to agents outside the boundary
+    pub outside_rule: OutsideRule,
+    /// Number of steps agents may stay in a safe zone, after which they are
+    /// moved to the nearest free cell outside of the safe zones
+    pub safe_zone_stay_limit: Option<usize>,
+    /// Kinds of agents the population is made up of
+    pub agent_types: Vec<AgentType>,
+    /// Stamina every agent starts with, and can not exceed
+    pub max_stamina: f32,
+    /// Stamina used up by a step
+    pub step_stamina_cost: f32,
+    /// Stamina restored by standing still
+    pub rest_stamina_gain: f32,
+    /// Number of steps after tagging during which the agent can not tag again
+    pub tag_cooldown: usize,
+    /// Restrictions on tag-backs and re-tags
+    pub tag_back_rules: TagBackRules,
+    /// Extra sprint distance of agents which are 'it'
+    pub it_speed_bonus: usize,
+    /// Stamina used up by a sprint
+    pub sprint_stamina_cost: f32,
+    /// Number of steps a sprint takes, 0 is taken as 1
+    pub sprint_duration: usize,
+}
+
+impl Config {
+    /// Topology of the grid, continuous space uses a square grid only to
+    /// find agents near a point
+    pub fn grid_topology(&self) -> Topology {
+        match self.space {
+            Space::Discrete => self.topology,
+            Space::Continuous => Topology::VonNeumann,
+        }
+    }
+
+    /// Checks the indices the config holds into its own lists, which would
+    /// otherwise only fail deep into a run
+    pub fn check(&self) -> Result<(), String> {
+        for (i, spawn_point) in self.spawn_points.iter().enumerate() {
+            if spawn_point.team >= self.teams.len() {
+                return Err(format!(
+                    "spawn point {} adds runners to team {}, but there are only {} teams",
+                    i,
+                    spawn_point.team,
+                    self.teams.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of levels of the grid, continuous space is always flat
+    pub fn grid_levels(&self) -> usize {
+        match self.space {
+            Space::Discrete => self.levels,
+            Space::Continuous => 1,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            game_mode: GAME_MODE,
+            schedule: SCHEDULE,
+            space: SPACE,
+            topology: TOPOLOGY,
+            steer_angle: STEER_ANGLE,
+            num_rounds: NUM_ROUNDS,
+            num_steps: NUM_STEPS,
+            num_agents: NUM_AGENTS,
+            num_agents_it: NUM_AGENTS_IT,
+            spawn_points: SPAWN_POINTS.to_vec(),
+            rules: Rules::new(GAME_MODE, INFECTION_RECOVERY_STEPS),
+            infection_curve_path: INFECTION_CURVE_PATH,
+            teams: TEAMS.to_vec(),
+            safe_zones: SAFE_ZONES.to_vec(),
+            terrain: TERRAIN.to_vec(),
+            terrain_effects: TERRAIN_EFFECTS,
+            obstacles: OBSTACLES.to_vec(),
+            doors: DOORS.to_vec(),
+            boundary: BOUNDARY,
+            outside_rule: OUTSIDE_RULE,
+            safe_zone_stay_limit: SAFE_ZONE_STAY_LIMIT,
+            grid_side: GRID_SIDE,
+            levels: GRID_LEVELS,
+            view_level: VIEW_LEVEL,
+            collision_detection: COLLSION_DETECTION,
+            validate: VALIDATE,
+            seed: SEED,
+            agent_types: AGENT_TYPES.to_vec(),
+            max_stamina: MAX_STAMINA,
+            step_stamina_cost: STEP_STAMINA_COST,
+            rest_stamina_gain: REST_STAMINA_GAIN,
+            tag_cooldown: TAG_COOLDOWN,
+            tag_back_rules: TAG_BACK_RULES,
+            it_speed_bonus: IT_SPEED_BONUS,
+            sprint_stamina_cost: SPRINT_STAMINA_COST,
+            sprint_duration: SPRINT_DURATION,
+        }
+    }
+}
@@ -2,9 +2,11 @@ extern crate glutin_window;
 extern crate graphics;
 extern crate opengl_graphics;
 
-use crate::{BODY_PIXEL_SIZE, GRID_SIDE, WINDOW_SIDE, LAST_UNTAGGED_DISPLAY_LENGTH};
+use crate::{LAST_UNTAGGED_DISPLAY_LENGTH, WINDOW_SIDE};
 
-use crate::grid::Position;
+use crate::config::{Config, Terrain, Topology};
+use crate::grid::{Grid, Point};
+use crate::state::State;
 
 use glutin_window::GlutinWindow as Window;
 use graphics::*;
@@ -17,26 +19,167 @@ const WHITE: [f32; 4] = [0.75, 0.75, 0.75, 1.0];
 const RED: [f32; 4] = [1.0, 0.0, 0.0, 0.8];
 const YELLOW: [f32; 4] = [1.0, 1.0, 0.0, 0.8];
 const ORANGE: [f32; 4] = [1.0, 0.5, 0.0, 0.8];
+const BLUE: [f32; 4] = [0.2, 0.4, 1.0, 0.8];
+const GREY: [f32; 4] = [0.4, 0.4, 0.4, 0.8];
+const LIGHT_GREEN: [f32; 4] = [0.6, 0.85, 0.6, 1.0];
+const SAND: [f32; 4] = [0.9, 0.83, 0.6, 1.0];
+const MUD: [f32; 4] = [0.55, 0.45, 0.35, 1.0];
+const WATER: [f32; 4] = [0.55, 0.7, 0.95, 1.0];
+const DARK_GREY: [f32; 4] = [0.25, 0.25, 0.25, 1.0];
+/// Shade over the area outside the boundary
+const OUTSIDE: [f32; 4] = [0.9, 0.3, 0.3, 0.3];
+/// Colours of teams in team tag, reused if there are more teams
+const TEAM_COLORS: [[f32; 4]; 6] = [
+    [0.9, 0.1, 0.1, 0.8],
+    [0.1, 0.7, 0.1, 0.8],
+    [0.1, 0.2, 0.9, 0.8],
+    [0.9, 0.8, 0.0, 0.8],
+    [0.6, 0.1, 0.8, 0.8],
+    [0.0, 0.7, 0.8, 0.8],
+];
 
-pub type RenderObject = (Position, bool, usize);
+pub struct RenderObject {
+    pub position: Point,
+    pub state: State,
+    /// Steps since the agent last tagged someone
+    pub last_un_tagged: usize,
+    pub body_side: usize,
+    /// Team of the agent, if playing team tag
+    pub team: Option<usize>,
+}
 
 pub struct Graphics {
     gl: GlGraphics,
+    /// Distance between the centres of neighbouring cells on screen
     scale: f64,
+    grid_side: usize,
+    topology: Topology,
+    /// Number of levels of the grid
+    levels: usize,
+    /// Level shown, or None to show all levels projected onto the screen
+    view_level: Option<usize>,
 }
 
 impl Graphics {
-    pub fn render(&mut self, args: &RenderArgs, objects: &Vec<RenderObject>) {
-        let circle = ellipse::circle(0.0, 0.0, BODY_PIXEL_SIZE as f64 / 2.0 as f64);
+    /// Screen coordinates of a point of the grid. Hex grids are drawn with
+    /// pointy-topped cells, so that the grid is a rhombus
+    fn to_screen(topology: Topology, scale: f64, x: f64, y: f64) -> [f64; 2] {
+        match topology {
+            Topology::Hex => [(x + y / 2.0) * scale, y * scale * 3f64.sqrt() / 2.0],
+            _ => [x * scale, y * scale],
+        }
+    }
+
+    /// Background colour of the terrain, None for grass which is left blank
+    fn terrain_color(terrain: Terrain) -> Option<[f32; 4]> {
+        match terrain {
+            Terrain::Grass => None,
+            Terrain::Sand => Some(SAND),
+            Terrain::Mud => Some(MUD),
+            Terrain::Water => Some(WATER),
+        }
+    }
+
+    /// Colour of an agent on the level, darkened the further the level is
+    /// below the top one so that higher agents stand out in a projection
+    fn shaded(color: [f32; 4], level: usize, levels: usize) -> [f32; 4] {
+        let depth: f32 = (levels - 1 - level.min(levels - 1)) as f32 / levels as f32;
+        let factor: f32 = 1.0 - 0.6 * depth;
+        [
+            color[0] * factor,
+            color[1] * factor,
+            color[2] * factor,
+            color[3],
+        ]
+    }
+
+    /// Draws the terrain, safe zones, obstacles, closed doors and boundary
+    /// of the grid, and the agents on top
+    pub fn render(&mut self, args: &RenderArgs, objects: &[RenderObject], grid: &Grid) {
         let scale = self.scale;
+        let grid_side = self.grid_side;
+        let topology = self.topology;
+        let levels = self.levels;
+        let to_screen = |x: f64, y: f64| Graphics::to_screen(topology, scale, x, y);
+        // Corners on screen of the cells from (x_start, y_start) up to, but
+        // not including, (x_end, y_end)
+        let corners = |(x_start, x_end): (usize, usize), (y_start, y_end): (usize, usize)| {
+            let (x_start, x_end) = (x_start as f64, x_end as f64);
+            let (y_start, y_end) = (y_start as f64, y_end as f64);
+            [
+                to_screen(x_start, y_start),
+                to_screen(x_end, y_start),
+                to_screen(x_end, y_end),
+                to_screen(x_start, y_end),
+            ]
+        };
+        // A slice shows the agents on its level, a projection shows all of
+        // them, drawing higher levels over lower ones
+        let mut visible: Vec<&RenderObject> = match self.view_level {
+            Some(level) => objects
+                .iter()
+                .filter(|obj| obj.position.z as usize == level)
+                .collect(),
+            None => objects.iter().collect(),
+        };
+        visible.sort_by_key(|obj| obj.position.z as usize);
 
         self.gl.draw(args.viewport(), |c, gl| {
             clear(WHITE, gl);
-            for obj in objects {
-                let color = if obj.1 { RED } else { if obj.2 > LAST_UNTAGGED_DISPLAY_LENGTH {YELLOW} else {ORANGE}};
-                let transform = c
-                    .transform
-                    .trans(obj.0.x as f64 * scale, obj.0.y as f64 * scale);
+            // Every column is drawn as strips of cells with the same terrain
+            for (x, column) in grid.terrain.iter().enumerate() {
+                let mut y_start: usize = 0;
+                for y in 1..=column.len() {
+                    if y < column.len() && column[y] == column[y_start] {
+                        continue;
+                    }
+                    if let Some(color) = Graphics::terrain_color(column[y_start]) {
+                        polygon(color, &corners((x, x + 1), (y_start, y)), c.transform, gl);
+                    }
+                    y_start = y;
+                }
+            }
+            for zone in &grid.safe_zones {
+                let (x_range, y_range) = zone.cell_ranges(grid_side);
+                polygon(LIGHT_GREEN, &corners(x_range, y_range), c.transform, gl);
+            }
+            for region in &grid.blocked {
+                let (x_range, y_range) = region.cell_ranges(grid_side);
+                polygon(DARK_GREY, &corners(x_range, y_range), c.transform, gl);
+            }
+            if grid.boundary.is_some() {
+                // Bands left and right of the boundary, then above and below it
+                let ((x_start, x_end), (y_start, y_end)) = grid.allowed.cell_ranges(grid_side);
+                let bands = [
+                    ((0, x_start), (0, grid_side)),
+                    ((x_end, grid_side), (0, grid_side)),
+                    ((x_start, x_end), (0, y_start)),
+                    ((x_start, x_end), (y_end, grid_side)),
+                ];
+                for &(x_range, y_range) in &bands {
+                    if x_range.0 < x_range.1 && y_range.0 < y_range.1 {
+                        polygon(OUTSIDE, &corners(x_range, y_range), c.transform, gl);
+                    }
+                }
+            }
+            for obj in visible {
+                let color = if let Some(team) = obj.team {
+                    TEAM_COLORS[team % TEAM_COLORS.len()]
+                } else {
+                    match obj.state {
+                        State::It => RED,
+                        State::Frozen => BLUE,
+                        State::Recovered | State::Eliminated => GREY,
+                        State::Runner if obj.last_un_tagged > LAST_UNTAGGED_DISPLAY_LENGTH => {
+                            YELLOW
+                        }
+                        State::Runner => ORANGE,
+                    }
+                };
+                let color = Graphics::shaded(color, obj.position.z as usize, levels);
+                let circle = ellipse::circle(0.0, 0.0, obj.body_side as f64 * scale / 2.0);
+                let [x, y] = to_screen(obj.position.x as f64, obj.position.y as f64);
+                let transform = c.transform.trans(x, y);
                 ellipse(color, circle, transform, gl);
             }
         });
@@ -50,7 +193,8 @@ pub struct Display {
 }
 
 impl Display {
-    pub fn new() -> Display {
+    /// Opens a window showing the grid of the config, or one level of it
+    pub fn new(config: &Config) -> Display {
         let opengl = OpenGL::V3_2;
 
         let window: Window = WindowSettings::new("Tag!", [WINDOW_SIDE, WINDOW_SIDE])
@@ -59,9 +203,20 @@ impl Display {
             .build()
             .unwrap();
 
+        let grid_side = config.grid_side;
+        let topology = config.grid_topology();
+        // A hex grid is half as wide again as it is high
+        let width: f64 = match topology {
+            Topology::Hex => grid_side as f64 * 1.5,
+            _ => grid_side as f64,
+        };
         let graphics = Graphics {
             gl: GlGraphics::new(opengl),
-            scale: WINDOW_SIDE / GRID_SIDE as f64,
+            scale: WINDOW_SIDE / width,
+            grid_side,
+            topology,
+            levels: config.grid_levels(),
+            view_level: config.view_level,
         };
 
         let settings = EventSettings {
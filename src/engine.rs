@@ -1,10 +1,75 @@
 use crate::action::*;
-use crate::agent::AgentManager;
+use crate::agent::{AgentManager, Id};
+use crate::config::{Config, GameMode, OutsideRule, Schedule};
 use crate::display::Display;
 use crate::grid::Grid;
+use crate::state::State;
+use crate::validation::Validator;
 
 use piston::input::RenderEvent;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::fs::File;
+use std::io::{self, Write};
+
+/// Number of susceptible, infected and recovered agents
+type SirCounts = (usize, usize, usize);
+
+/// Reason a round ended
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Termination {
+    /// The round reached its maximum number of steps
+    TimeLimit,
+    /// At most one runner is left
+    LastRunnerStanding,
+    /// No runner is left who is not frozen
+    AllFrozen,
+    /// No one is left to infect
+    AllInfected,
+    /// No one is infected anymore
+    NoneInfected,
+    /// All agents are in the same team
+    OneTeamLeft,
+    /// The viewer was closed before the round ended
+    ViewerClosed,
+}
+
+impl Termination {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Termination::TimeLimit => "time limit reached",
+            Termination::LastRunnerStanding => "last runner standing",
+            Termination::AllFrozen => "all runners frozen",
+            Termination::AllInfected => "everyone infected",
+            Termination::NoneInfected => "no one infected",
+            Termination::OneTeamLeft => "one team left",
+            Termination::ViewerClosed => "viewer closed",
+        }
+    }
+}
+
+/// Outcome of a finished round
+pub struct RoundResult {
+    /// Number of steps the round took
+    pub steps: usize,
+    pub termination: Termination,
+    /// Number of times the 'Tag' action was used
+    pub tagged_count: usize,
+    /// Number of runners left at the end
+    pub runners: usize,
+    /// Number of times runners were frozen
+    pub frozen_count: usize,
+    /// Number of times frozen agents were released
+    pub unfrozen_count: usize,
+    /// Number of captures and size at the end of every team
+    pub team_stats: Vec<(usize, usize)>,
+    /// Number of agents of every type at the end
+    pub type_counts: Vec<(&'static str, usize)>,
+}
+
 pub struct Engine {
+    /// Parameters the grid and agents are created from at the start of a round
+    config: Config,
     /// 2D grid, which is used for collision detection and 'tagging'
     grid: Grid,
     /// All required information on choosing actions
@@ -14,42 +79,228 @@ pub struct Engine {
     display: Option<Display>,
     show_graphics: bool,
     step_counter: usize,
+    /// Number of steps done in the current round
+    round_step_counter: usize,
+    /// Results of the finished rounds
+    results: Vec<RoundResult>,
+    /// Whether the viewer window has been closed
+    window_closed: bool,
+    /// Rules of the game being played
+    game_mode: GameMode,
+    /// In infection mode, SIR counts at every step of the current round,
+    /// starting from the initial state
+    infection_curve: Vec<SirCounts>,
+    /// File to which the infection curve is written at the end, if at all
+    infection_curve_path: Option<&'static str>,
+    /// Names of the teams in team tag
+    team_names: Vec<&'static str>,
+    /// Checks the grid and agents after every step, if validation is on
+    validator: Option<Validator>,
 }
 
 impl Engine {
-    pub fn new(grid: Grid, ac: ActionContext, am: AgentManager, show_graphics: bool) -> Engine {
+    pub fn new(
+        config: &Config,
+        grid: Grid,
+        ac: ActionContext,
+        am: AgentManager,
+        show_graphics: bool,
+    ) -> Engine {
+        if let Err(error) = config.check() {
+            panic!("Invalid config: {}", error);
+        }
         let mut display: Option<Display> = None;
         if show_graphics {
-            display = Some(Display::new());
+            display = Some(Display::new(config));
+        }
+        let mut infection_curve: Vec<SirCounts> = vec![];
+        if config.game_mode == GameMode::Infection {
+            infection_curve.push(am.get_sir_counts());
+        }
+        let mut validator: Option<Validator> = None;
+        if config.validate {
+            validator = Some(Validator::new(config, &am));
         }
         Engine {
+            config: config.clone(),
             grid,
             ac,
             am,
             display,
             show_graphics,
             step_counter: 0,
+            round_step_counter: 0,
+            results: vec![],
+            window_closed: false,
+            game_mode: config.game_mode,
+            infection_curve,
+            infection_curve_path: config.infection_curve_path,
+            team_names: config.teams.iter().map(|t| t.name).collect(),
+            validator,
         }
     }
 
     fn update(&mut self) {
-        self.am.perform_actions(&self.grid, &self.ac);
-        self.grid.update(self.am.flush_log());
+        match self.config.schedule {
+            Schedule::Synchronous => {
+                self.am.perform_actions(&self.grid, &self.ac);
+                self.grid.update(self.am.flush_log());
+            }
+            Schedule::RandomSequential | Schedule::PoissonAsynchronous => {
+                let order: Vec<usize> = self.activation_order();
+                self.am
+                    .perform_actions_sequentially(&mut self.grid, &self.ac, &order);
+            }
+        }
         self.step_counter += 1;
+        self.round_step_counter += 1;
+        self.grid.advance(self.round_step_counter);
+        if self.grid.boundary.is_some() {
+            self.apply_outside_rule();
+        }
+        self.am.evict_overstayers(&mut self.grid);
+        // Eliminated agents leave the game, and new runners join it
+        for id in self.am.get_ids_in_state(State::Eliminated) {
+            self.am.remove_agent(id, &mut self.grid);
+        }
+        for spawn_point in &self.config.spawn_points {
+            if self.round_step_counter.checked_rem(spawn_point.interval) == Some(0) {
+                self.am.add_agent(
+                    State::Runner,
+                    spawn_point.team,
+                    spawn_point.region,
+                    None,
+                    &mut self.grid,
+                );
+            }
+        }
+        if let Some(validator) = &self.validator {
+            for violation in validator.check(&self.am, &self.grid) {
+                eprintln!("Step {}: {}", self.step_counter, violation);
+            }
+        }
+        if self.game_mode == GameMode::Infection {
+            self.infection_curve.push(self.am.get_sir_counts());
+        }
+        let maybe_termination =
+            self.am
+                .termination()
+                .or(if self.round_step_counter >= self.config.num_steps {
+                    Some(Termination::TimeLimit)
+                } else {
+                    None
+                });
+        if let Some(termination) = maybe_termination {
+            self.end_round(termination);
+            if !self.is_finished() {
+                self.reset();
+            }
+        }
+    }
+
+    /// Records the result of the current round, whose agent manager holds
+    /// the counts of this round only
+    fn end_round(&mut self, termination: Termination) {
+        self.results.push(RoundResult {
+            steps: self.round_step_counter,
+            termination,
+            tagged_count: self.am.get_tagged_count(),
+            runners: self.am.get_state_count(State::Runner),
+            frozen_count: self.am.get_transition_count(State::Runner, State::Frozen),
+            unfrozen_count: self.am.get_transition_count(State::Frozen, State::Runner),
+            team_stats: self.am.get_team_stats(),
+            type_counts: self.am.get_type_counts(),
+        });
+    }
+
+    /// Deals with the agents outside the boundary as the config says
+    fn apply_outside_rule(&mut self) {
+        let outside: Vec<Id> = self
+            .am
+            .get_ids()
+            .iter()
+            .copied()
+            .filter(|&id| !self.grid.is_allowed(self.am.get_position(id)))
+            .collect();
+        for id in outside {
+            match self.config.outside_rule {
+                OutsideRule::Eliminate => {
+                    self.am.remove_agent(id, &mut self.grid);
+                }
+                OutsideRule::Teleport => {
+                    let allowed = self.grid.allowed;
+                    self.am.relocate(id, allowed, &mut self.grid);
+                }
+                OutsideRule::Drain(amount) => self.am.use_stamina(id, amount),
+            }
+        }
+    }
+
+    /// Indices of the agents in the order in which they act this step, for
+    /// the sequential schedules
+    fn activation_order(&mut self) -> Vec<usize> {
+        let agent_count: usize = self.am.get_agent_count();
+        let rng = &mut self.am.rng;
+        match self.config.schedule {
+            Schedule::PoissonAsynchronous => {
+                // Exponential waiting times between the ticks of every clock
+                let mut ticks: Vec<(f64, usize)> = vec![];
+                for i in 0..agent_count {
+                    let mut t: f64 = -(1.0 - rng.gen::<f64>()).ln();
+                    while t < 1.0 {
+                        ticks.push((t, i));
+                        t -= (1.0 - rng.gen::<f64>()).ln();
+                    }
+                }
+                ticks.sort_by(|a, b| a.0.total_cmp(&b.0));
+                ticks.into_iter().map(|(_, i)| i).collect()
+            }
+            _ => {
+                let mut order: Vec<usize> = (0..agent_count).collect();
+                order.shuffle(rng);
+                order
+            }
+        }
+    }
+
+    /// Starts a new round with a new grid and population
+    fn reset(&mut self) {
+        self.grid = Grid::new(&self.config);
+        // Every round has its own seed, which follows from the first one
+        let seed: u64 = self.am.rng.gen();
+        self.am = AgentManager::with_seed(&self.config, &self.ac, &mut self.grid, seed);
+        self.round_step_counter = 0;
+        if self.validator.is_some() {
+            self.validator = Some(Validator::new(&self.config, &self.am));
+        }
+        self.infection_curve.clear();
+        if self.game_mode == GameMode::Infection {
+            self.infection_curve.push(self.am.get_sir_counts());
+        }
+    }
+
+    /// Whether all rounds have been played, or the viewer has been closed
+    pub fn is_finished(&self) -> bool {
+        self.window_closed || self.results.len() >= self.config.num_rounds
     }
 
     pub fn step(&mut self) {
         if self.show_graphics {
             let display: &mut Display = self.display.as_mut().unwrap();
             let maybe_e = display.events.next(&mut display.window);
-            if maybe_e.is_some() {
+            if let Some(e) = maybe_e {
                 self.update();
-                let e = maybe_e.unwrap();
                 let render_objects = self.am.get_render_info();
                 let display: &mut Display = self.display.as_mut().unwrap();
 
                 if let Some(args) = e.render_args() {
-                    display.graphics.render(&args, &render_objects);
+                    display.graphics.render(&args, &render_objects, &self.grid);
+                }
+            } else {
+                self.window_closed = true;
+                // The round cut short still counts towards the stats
+                if self.round_step_counter > 0 {
+                    self.end_round(Termination::ViewerClosed);
                 }
             }
         } else {
@@ -57,11 +308,108 @@ impl Engine {
         }
     }
 
+    /// Prints the result of every round, and counts summed over all rounds
     pub fn stats(&mut self) {
+        let total =
+            |count: fn(&RoundResult) -> usize| -> usize { self.results.iter().map(count).sum() };
         println!(
             "Steps done: {} \nNumber of times tagged: {}",
             self.step_counter,
-            self.am.get_tagged_count()
-        )
+            total(|result| result.tagged_count)
+        );
+        if self.game_mode == GameMode::Freeze {
+            println!(
+                "Number of times frozen: {} \nNumber of times unfrozen: {}",
+                total(|result| result.frozen_count),
+                total(|result| result.unfrozen_count)
+            );
+        }
+        for (round, result) in self.results.iter().enumerate() {
+            println!(
+                "Round {}: {} after {} steps, {} tags, {} runners left",
+                round + 1,
+                result.termination.describe(),
+                result.steps,
+                result.tagged_count,
+                result.runners
+            );
+        }
+        if self.game_mode == GameMode::Infection {
+            self.infection_stats();
+        }
+        if self.game_mode == GameMode::Team {
+            for (team_ix, team) in self.team_names.iter().enumerate() {
+                println!(
+                    "Team '{}': {} captures, {} agents left in the last round",
+                    team,
+                    self.results
+                        .iter()
+                        .map(|result| result.team_stats[team_ix].0)
+                        .sum::<usize>(),
+                    self.results
+                        .last()
+                        .map_or(0, |result| result.team_stats[team_ix].1)
+                );
+            }
+        }
+        if let Some(last) = self.results.last() {
+            for (type_ix, &(name, _)) in last.type_counts.iter().enumerate() {
+                println!(
+                    "Agents of type '{}': {}",
+                    name,
+                    self.results
+                        .iter()
+                        .map(|result| result.type_counts[type_ix].1)
+                        .sum::<usize>()
+                );
+            }
+        }
+    }
+
+    /// SIR summary of the last round of an infection game, and its
+    /// infection curve written to file
+    fn infection_stats(&self) {
+        let curve: &Vec<SirCounts> = &self.infection_curve;
+        let (s, i, r) = curve[curve.len() - 1];
+        let n = s + i + r;
+        let (peak_step, peak) = curve
+            .iter()
+            .enumerate()
+            .map(|(step, &(_, i, _))| (step, i))
+            .max_by_key(|&(step, i)| (i, std::cmp::Reverse(step)))
+            .unwrap();
+        if self.results.len() > 1 {
+            println!("In the last round:");
+        }
+        println!("Susceptible: {} \nInfected: {} \nRecovered: {}", s, i, r);
+        // All agents may have been removed by the end of the round
+        let attack_rate: String = if n == 0 {
+            String::from("n/a, no agents were left")
+        } else {
+            format!("{:.1}%", 100.0 * (n - s) as f32 / n as f32)
+        };
+        println!(
+            "Peak infected: {} at step {} \nAttack rate: {}",
+            peak, peak_step, attack_rate
+        );
+        match curve.iter().position(|&(s, _, _)| s == 0) {
+            Some(step) => println!("Time to full infection: {} steps", step),
+            None => println!("Time to full infection: not reached"),
+        }
+        if let Some(path) = self.infection_curve_path {
+            match self.write_infection_curve(path) {
+                Ok(()) => println!("Infection curve written to {}", path),
+                Err(e) => eprintln!("Could not write infection curve to {}: {}", path, e),
+            }
+        }
+    }
+
+    fn write_infection_curve(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(file, "step,susceptible,infected,recovered")?;
+        for (step, (s, i, r)) in self.infection_curve.iter().enumerate() {
+            writeln!(file, "{},{},{},{}", step, s, i, r)?;
+        }
+        Ok(())
     }
 }
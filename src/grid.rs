@@ -1,105 +1,512 @@
-use crate::GRID_SIDE;
-
 use crate::agent::Id;
+use crate::config::{Boundary, Config, Door, Obstacle, Terrain, TerrainPatch, Topology};
 use crate::rand::Rng;
 
+use rand::rngs::StdRng;
+use rayon::prelude::*;
+use smallvec::SmallVec;
+use std::array;
 use std::cmp;
+use std::iter;
+
+/// Cell of a grid with `D` axes: the column and row within a level, and
+/// the place of the level along every further axis. The worlds of the
+/// game have three, z being the level, which is 0 in a flat world
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Position<const D: usize = 3>(pub [usize; D]);
+
+impl<const D: usize> Position<D> {
+    pub fn x(&self) -> usize {
+        self.0[0]
+    }
+
+    pub fn y(&self) -> usize {
+        self.0[1]
+    }
+
+    /// Position moved by (dx, dy) on its level, or None if it would leave a
+    /// grid of side `grid_side`
+    pub fn shifted(&self, dx: i64, dy: i64, grid_side: usize) -> Option<Position<D>> {
+        self.moved_along(0, dx, grid_side)?
+            .moved_along(1, dy, grid_side)
+    }
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
-pub struct Position {
-    pub x: usize,
-    pub y: usize,
+    /// Position moved by `d` cells along the axis, or None if it would leave
+    /// the `len` cells of the grid along it
+    pub fn moved_along(&self, axis: usize, d: i64, len: usize) -> Option<Position<D>> {
+        let c = self.0[axis] as i64 + d;
+        if c < 0 || c >= len as i64 {
+            None
+        } else {
+            let mut moved = *self;
+            moved.0[axis] = c as usize;
+            Some(moved)
+        }
+    }
+
+    /// Chebyshev distance, i.e. the distance at which positions are within
+    /// each other's square subgrids
+    pub fn distance(&self, other: Position<D>) -> usize {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .map(|(&a, &b)| (a as i64 - b as i64).unsigned_abs() as usize)
+            .max()
+            .unwrap_or(0)
+    }
 }
 
 impl Position {
-    pub fn random() -> Position {
-        let mut rng = rand::thread_rng();
-        Position {
-            x: rng.gen_range(0, GRID_SIDE),
-            y: rng.gen_range(0, GRID_SIDE),
+    pub fn new(x: usize, y: usize, z: usize) -> Position {
+        Position([x, y, z])
+    }
+
+    pub fn z(&self) -> usize {
+        self.0[2]
+    }
+
+    /// Position moved by `dz` levels, or None if it would leave a grid with
+    /// `levels` levels
+    pub fn climbed(&self, dz: i64, levels: usize) -> Option<Position> {
+        self.moved_along(2, dz, levels)
+    }
+}
+
+/// Point in continuous space, measured in cells. Agents at a point are kept
+/// in the grid at the cell containing it, which serves as a spatial index
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Point {
+    /// Cell containing the point
+    pub fn cell(&self) -> Position {
+        Position::new(self.x as usize, self.y as usize, self.z as usize)
+    }
+
+    pub fn distance(&self, other: Point) -> f32 {
+        (self.x - other.x)
+            .hypot(self.y - other.y)
+            .hypot(self.z - other.z)
+    }
+
+    /// Point moved by `distance` in the direction `heading` on its level,
+    /// given in radians clockwise from the positive x axis, or None if it
+    /// would leave a grid of side `grid_side`
+    pub fn moved(&self, heading: f32, distance: f32, grid_side: usize) -> Option<Point> {
+        let x = self.x + distance * heading.cos();
+        let y = self.y + distance * heading.sin();
+        let side = grid_side as f32;
+        if x < 0.0 || y < 0.0 || x >= side || y >= side {
+            None
+        } else {
+            Some(Point { x, y, z: self.z })
         }
     }
 }
 
+impl From<Position> for Point {
+    fn from(position: Position) -> Point {
+        Point {
+            x: position.x() as f32,
+            y: position.y() as f32,
+            z: position.z() as f32,
+        }
+    }
+}
+
+/// Rectangular area of the grid, given in fractions of the grid side so
+/// that it does not depend on the grid size. It spans all levels
 #[derive(Copy, Clone)]
-pub struct PositionChange {
+pub struct Region {
+    /// Left and right edge
+    pub x: (f32, f32),
+    /// Top and bottom edge
+    pub y: (f32, f32),
+}
+
+impl Region {
+    pub const WHOLE: Region = Region {
+        x: (0.0, 1.0),
+        y: (0.0, 1.0),
+    };
+
+    /// Random cell on the first level of the region, or None if the region
+    /// does not intersect the grid
+    pub fn random_position<const D: usize>(
+        &self,
+        grid_side: usize,
+        rng: &mut StdRng,
+    ) -> Option<Position<D>> {
+        let ((x_start, x_end), (y_start, y_end)) = self.cell_ranges(grid_side);
+        if x_start == x_end || y_start == y_end {
+            return None;
+        }
+        let mut position = Position([0; D]);
+        position.0[0] = rng.gen_range(x_start, x_end);
+        position.0[1] = rng.gen_range(y_start, y_end);
+        Some(position)
+    }
+
+    /// Region moved by the given fractions of the grid side
+    pub fn shifted(&self, dx: f32, dy: f32) -> Region {
+        Region {
+            x: (self.x.0 + dx, self.x.1 + dx),
+            y: (self.y.0 + dy, self.y.1 + dy),
+        }
+    }
+
+    pub fn contains<const D: usize>(&self, position: Position<D>, grid_side: usize) -> bool {
+        let ((x_start, x_end), (y_start, y_end)) = self.cell_ranges(grid_side);
+        (x_start..x_end).contains(&position.x()) && (y_start..y_end).contains(&position.y())
+    }
+
+    /// Number of cells between the position and the nearest cell of the
+    /// region, in the direction in which it is further, or None if the
+    /// region covers no cells
+    pub fn distance<const D: usize>(
+        &self,
+        position: Position<D>,
+        grid_side: usize,
+    ) -> Option<usize> {
+        let ((x_start, x_end), (y_start, y_end)) = self.cell_ranges(grid_side);
+        if x_start == x_end || y_start == y_end {
+            return None;
+        }
+        let along = |p: usize, start: usize, end: usize| {
+            if p < start {
+                start - p
+            } else {
+                p.saturating_sub(end - 1)
+            }
+        };
+        Some(cmp::max(
+            along(position.x(), x_start, x_end),
+            along(position.y(), y_start, y_end),
+        ))
+    }
+
+    /// Number of cells between the position and the nearest cell outside
+    /// of the region, 0 if the position is outside of it
+    pub fn depth<const D: usize>(&self, position: Position<D>, grid_side: usize) -> usize {
+        if !self.contains(position, grid_side) {
+            return 0;
+        }
+        let ((x_start, x_end), (y_start, y_end)) = self.cell_ranges(grid_side);
+        let (x, y) = (position.x(), position.y());
+        1 + (x - x_start)
+            .min(x_end - 1 - x)
+            .min(y - y_start)
+            .min(y_end - 1 - y)
+    }
+
+    /// Half-open ranges of the cells covered by the region horizontally
+    /// and vertically
+    pub fn cell_ranges(&self, grid_side: usize) -> ((usize, usize), (usize, usize)) {
+        (self.cells(self.x, grid_side), self.cells(self.y, grid_side))
+    }
+
+    /// Range of cells covered by the fractions `(start, end)` of the grid
+    /// side, which includes at least one cell if the fractions intersect
+    /// the grid and is empty otherwise
+    fn cells(&self, (start, end): (f32, f32), grid_side: usize) -> (usize, usize) {
+        // A degenerate range still covers the cell it lies in
+        let intersects = start < 1.0 && (end > 0.0 || (end == 0.0 && start == end));
+        if !intersects {
+            return (0, 0);
+        }
+        let start = ((start.max(0.0) * grid_side as f32) as usize).min(grid_side - 1);
+        let end = ((end * grid_side as f32).ceil() as usize).min(grid_side);
+        (start, end.max(start + 1))
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PositionChange<const D: usize = 3> {
     pub id: Id,
-    pub before: Position,
-    pub after: Position,
+    pub before: Position<D>,
+    pub after: Position<D>,
+}
+
+/// Agents occupying a cell. Without collision detection several agents
+/// may share a cell, but most cells hold at most one, which is kept inline
+pub type Cell = SmallVec<[Id; 2]>;
+
+/// Number of position changes from which `Grid::update` writes in parallel
+const PARALLEL_UPDATE_MIN_CHANGES: usize = 4096;
+
+fn remove_from(cell: &mut Cell, id: Id) {
+    if let Some(k) = cell.iter().position(|&other| other == id) {
+        cell.remove(k);
+    }
 }
 
-/// Provides 2D grid and subgrid agent detection
-pub struct Grid {
-    pub val: Vec<Vec<Id>>,
+/// Provides grid and subgrid agent detection on a grid with `D` axes. The
+/// cells are stored column by column, with every column holding its levels
+/// one after another, so the cell (x, y, z) of the game's worlds is
+/// `val[(x * levels + z) * side + y]` and a flat world is one with a single
+/// level
+pub struct Grid<const D: usize = 3> {
+    pub val: Vec<Cell>,
+    /// Number of cells along every axis
+    shape: [usize; D],
+    /// Distance in `val` between cells which are next to each other along
+    /// every axis
+    strides: [usize; D],
+    /// Which cells neighbour each other, and hence the shape of subgrids
+    pub topology: Topology,
+    /// Areas in which agents can not be tagged
+    pub safe_zones: Vec<Region>,
+    /// Terrain of every cell, indexed by column and row, which is the same
+    /// on every level
+    pub terrain: Vec<Vec<Terrain>>,
+    /// Moving rectangles agents can not move onto
+    pub obstacles: Vec<Obstacle>,
+    /// Areas agents can not move onto while they are closed
+    pub doors: Vec<Door>,
+    /// Shrinking boundary of the area agents are allowed in, if any
+    pub boundary: Option<Boundary>,
+    /// Areas of the obstacles and closed doors at the current step
+    pub blocked: Vec<Region>,
+    /// Area within the boundary at the current step
+    pub allowed: Region,
 }
 
 impl Grid {
-    pub fn set(&mut self, position: Position, id: Id) {
-        self.val[position.x][position.y] = id;
+    /// An empty grid with the size, safe zones, terrain and environment of
+    /// the config, as it is at the start of a round. Its levels are stacked
+    /// along the third axis
+    pub fn new(config: &Config) -> Grid {
+        Grid::with_levels(config, config.grid_levels())
+    }
+}
+
+impl<const D: usize> Grid<D> {
+    /// An empty grid like `Grid::new`, with `levels` cells along every axis
+    /// after the first two
+    pub fn with_levels(config: &Config, levels: usize) -> Grid<D> {
+        let mut shape = [levels; D];
+        shape[0] = config.grid_side;
+        shape[1] = config.grid_side;
+        let mut grid = Grid {
+            val: vec![Cell::new(); shape.iter().product()],
+            shape,
+            strides: strides(shape),
+            topology: config.grid_topology(),
+            safe_zones: config.safe_zones.clone(),
+            terrain: terrain_map(&config.terrain, config.grid_side),
+            obstacles: config.obstacles.clone(),
+            doors: config.doors.clone(),
+            boundary: config.boundary,
+            blocked: vec![],
+            allowed: Region::WHOLE,
+        };
+        grid.advance(0);
+        grid
+    }
+
+    /// Moves the obstacles, opens and closes the doors and shrinks the
+    /// boundary to where they are after `step` steps of the round
+    pub fn advance(&mut self, step: usize) {
+        self.blocked.clear();
+        self.blocked.extend(
+            self.obstacles
+                .iter()
+                .map(|obstacle| obstacle.region_at(step)),
+        );
+        self.blocked.extend(
+            self.doors
+                .iter()
+                .filter(|door| !door.is_open_at(step))
+                .map(|door| door.region),
+        );
+        if let Some(boundary) = self.boundary {
+            self.allowed = boundary.region_at(step);
+        }
+    }
+
+    /// Whether an obstacle or closed door covers the cell
+    pub fn is_blocked(&self, position: Position<D>) -> bool {
+        let grid_side = self.side();
+        self.blocked
+            .iter()
+            .any(|region| region.contains(position, grid_side))
+    }
+
+    /// Whether the cell is within the boundary
+    pub fn is_allowed(&self, position: Position<D>) -> bool {
+        self.allowed.contains(position, self.side())
+    }
+
+    /// Whether a move from one cell onto another gets an agent outside the
+    /// boundary closer to it
+    pub fn is_way_back(&self, from: Position<D>, to: Position<D>) -> bool {
+        let grid_side = self.side();
+        match (
+            self.allowed.distance(from, grid_side),
+            self.allowed.distance(to, grid_side),
+        ) {
+            (Some(before), Some(after)) => after < before,
+            _ => false,
+        }
+    }
+
+    /// Whether an agent can move from one cell onto another: the destination
+    /// must not be blocked, nor outside the boundary unless the agent
+    /// already is, so that it can always head back in
+    pub fn can_pass(&self, from: Position<D>, to: Position<D>) -> bool {
+        !self.is_blocked(to) && (self.is_allowed(to) || !self.is_allowed(from))
+    }
+
+    pub fn terrain_at(&self, position: Position<D>) -> Terrain {
+        self.terrain[position.x()][position.y()]
+    }
+
+    pub fn is_safe(&self, position: Position<D>) -> bool {
+        let grid_side = self.side();
+        self.safe_zones
+            .iter()
+            .any(|zone| zone.contains(position, grid_side))
+    }
+
+    /// Number of cells between the position and the nearest cell outside of
+    /// the safe zone it is deepest in, 0 if it is not in a safe zone
+    pub fn safe_depth(&self, position: Position<D>) -> usize {
+        let grid_side = self.side();
+        self.safe_zones
+            .iter()
+            .map(|zone| zone.depth(position, grid_side))
+            .max()
+            .unwrap_or(0)
     }
 
-    pub fn update(&mut self, changes: Vec<PositionChange>) {
+    /// Number of cells of a level along both of its axes
+    pub fn side(&self) -> usize {
+        self.shape[0]
+    }
+
+    /// Number of levels stacked in the grid, 1 in a flat world
+    pub fn levels(&self) -> usize {
+        self.shape[2..].iter().product()
+    }
+
+    /// Number of cells along every axis
+    pub fn shape(&self) -> [usize; D] {
+        self.shape
+    }
+
+    /// Index of the cell in `val`
+    fn index(&self, position: Position<D>) -> usize {
+        (0..D)
+            .map(|axis| position.0[axis] * self.strides[axis])
+            .sum()
+    }
+
+    /// Agents in the cell
+    pub fn cell(&self, position: Position<D>) -> &Cell {
+        &self.val[self.index(position)]
+    }
+
+    /// Every cell with its position, column by column and level by level
+    /// within a column
+    pub fn cells(&self) -> impl Iterator<Item = (Position<D>, &Cell)> + '_ {
+        self.val.iter().enumerate().map(move |(i, cell)| {
+            let position = array::from_fn(|axis| i / self.strides[axis] % self.shape[axis]);
+            (Position(position), cell)
+        })
+    }
+
+    pub fn add(&mut self, position: Position<D>, id: Id) {
+        let i = self.index(position);
+        self.val[i].push(id);
+    }
+
+    /// Removes the agent from the cell, leaving any other occupants
+    pub fn remove(&mut self, position: Position<D>, id: Id) {
+        let i = self.index(position);
+        remove_from(&mut self.val[i], id);
+    }
+
+    /// Applies the changes in order. Large batches are split into bands of
+    /// columns which are written in parallel, every band applying the
+    /// writes to its own cells in the original order, so the result is the
+    /// same as applying them one by one
+    pub fn update(&mut self, changes: Vec<PositionChange<D>>) {
+        if changes.len() < PARALLEL_UPDATE_MIN_CHANGES {
+            for change in &changes {
+                self.remove(change.before, change.id);
+                self.add(change.after, change.id);
+            }
+            return;
+        }
+        let grid_side: usize = self.side();
+        let band_width: usize = grid_side.div_ceil(rayon::current_num_threads()).max(1);
+        let band_count: usize = grid_side.div_ceil(band_width);
+        let band_len: usize = band_width * self.strides[0];
+        // Every write says whether the id is added to or removed from the cell
+        let mut writes: Vec<Vec<(usize, Id, bool)>> = vec![vec![]; band_count];
         for change in &changes {
-            self.set(change.before, 0);
-            self.set(change.after, change.id);
+            let before: usize = self.index(change.before);
+            let after: usize = self.index(change.after);
+            writes[before / band_len].push((before, change.id, false));
+            writes[after / band_len].push((after, change.id, true));
         }
+        self.val
+            .par_chunks_mut(band_len)
+            .zip(writes.par_iter())
+            .enumerate()
+            .for_each(|(band, (cells, writes))| {
+                for &(i, id, added) in writes {
+                    let cell: &mut Cell = &mut cells[i - band * band_len];
+                    if added {
+                        cell.push(id);
+                    } else {
+                        remove_from(cell, id);
+                    }
+                }
+            });
     }
 
     pub fn is_subgrid_free(
         &self,
-        position: Position,
-        subgrid_size_x: usize,
-        subgrid_size_y: usize,
+        position: Position<D>,
+        subgrid_size: [usize; D],
         excluded_ids: Vec<Id>,
         maybe_excluded_fn: Option<&dyn Fn(Id) -> bool>,
     ) -> bool {
-        let subgrid_center: Position = self.get_subgrid_center(subgrid_size_x, subgrid_size_y);
-        let mut occupier_iter: SubgridSearch = SubgridSearch::new(
-            position,
-            subgrid_center,
-            subgrid_size_x,
-            subgrid_size_y,
-            excluded_ids,
-            &self.val,
-        );
+        let mut occupier_iter: SubgridSearch<D> = self.search(position, subgrid_size, excluded_ids);
 
         if let Some(excluded_fn) = maybe_excluded_fn {
-            while let Some(occupier) = occupier_iter.next() {
+            for occupier in occupier_iter {
                 if !excluded_fn(occupier) {
                     return false;
                 }
             }
-            return true;
+            true
         } else {
-            return occupier_iter.next().is_none();
+            occupier_iter.next().is_none()
         }
     }
 
     pub fn is_subgrid_occupied(
         &self,
-        position: Position,
-        subgrid_size_x: usize,
-        subgrid_size_y: usize,
+        position: Position<D>,
+        subgrid_size: [usize; D],
         excluded_ids: Vec<Id>,
         maybe_excluded_fn: Option<&dyn Fn(Id) -> bool>,
     ) -> bool {
-        let subgrid_center: Position = self.get_subgrid_center(subgrid_size_x, subgrid_size_y);
-        let mut occupier_iter: SubgridSearch = SubgridSearch::new(
-            position,
-            subgrid_center,
-            subgrid_size_x,
-            subgrid_size_y,
-            excluded_ids,
-            &self.val,
-        );
+        let mut occupier_iter: SubgridSearch<D> = self.search(position, subgrid_size, excluded_ids);
 
         if let Some(excluded_fn) = maybe_excluded_fn {
-            while let Some(occupier) = occupier_iter.next() {
+            for occupier in occupier_iter {
                 if !excluded_fn(occupier) {
                     return true;
                 }
             }
-            return false;
+            false
         } else {
             occupier_iter.next().is_some()
         }
@@ -107,25 +514,16 @@ impl Grid {
 
     pub fn get_subgrid_occupiers(
         &self,
-        position: Position,
-        subgrid_size_x: usize,
-        subgrid_size_y: usize,
+        position: Position<D>,
+        subgrid_size: [usize; D],
         excluded_ids: Vec<Id>,
         maybe_excluded_fn: Option<&dyn Fn(Id) -> bool>,
     ) -> Vec<Id> {
-        let subgrid_center: Position = self.get_subgrid_center(subgrid_size_x, subgrid_size_y);
-        let mut occupier_iter: SubgridSearch = SubgridSearch::new(
-            position,
-            subgrid_center,
-            subgrid_size_x,
-            subgrid_size_y,
-            excluded_ids,
-            &self.val,
-        );
+        let occupier_iter: SubgridSearch<D> = self.search(position, subgrid_size, excluded_ids);
 
         if let Some(excluded_fn) = maybe_excluded_fn {
             let mut v: Vec<Id> = vec![];
-            while let Some(occupier) = occupier_iter.next() {
+            for occupier in occupier_iter {
                 if !excluded_fn(occupier) {
                     v.push(occupier);
                 }
@@ -136,93 +534,785 @@ impl Grid {
         }
     }
 
-    fn get_subgrid_center(&self, subgrid_size_x: usize, subgrid_size_y: usize) -> Position {
-        Position {
-            x: ((subgrid_size_x - 1) as f32 / 2.0).round() as usize,
-            y: ((subgrid_size_y - 1) as f32 / 2.0).round() as usize,
+    /// Whether the area swept by a body of side `body_side`, one level
+    /// high, moving `distance` cells from `position` in the direction
+    /// (dx, dy), is free. The body's starting square itself is not included
+    pub fn is_path_free(
+        &self,
+        position: Position<D>,
+        (dx, dy): (i64, i64),
+        distance: usize,
+        body_side: usize,
+        excluded_ids: Vec<Id>,
+    ) -> bool {
+        if distance == 0 {
+            return true;
+        }
+        let body: [usize; D] = body_size(body_side);
+        // Diagonal paths do not sweep a rectangle, so the body is checked
+        // at every cell along them
+        if dx != 0 && dy != 0 || self.topology == Topology::Hex {
+            let grid_side = self.side();
+            return (1..=distance as i64).all(|k| {
+                match position.shifted(dx * k, dy * k, grid_side) {
+                    Some(p) => self.is_subgrid_free(p, body, excluded_ids.clone(), None),
+                    None => false,
+                }
+            });
+        }
+        let center: Position<D> = self.get_subgrid_center(body);
+        let distance = distance as i64;
+        let mut corner: [i64; D] =
+            array::from_fn(|axis| position.0[axis] as i64 - center.0[axis] as i64);
+        corner[0] += cmp::min(dx, dx * distance);
+        corner[1] += cmp::min(dy, dy * distance);
+        let mut size: [usize; D] = body;
+        size[0] += ((distance - 1) * dx.abs()) as usize;
+        size[1] += ((distance - 1) * dy.abs()) as usize;
+        SubgridSearch::from_corner(corner, size, excluded_ids, self)
+            .next()
+            .is_none()
+    }
+
+    /// Search of the subgrid around the position, `subgrid_size` cells long
+    /// along every axis. On a hex grid, the subgrid stands for the stack of
+    /// hexagons of cells within its centre's distance of the position
+    fn search(
+        &self,
+        position: Position<D>,
+        subgrid_size: [usize; D],
+        excluded_ids: Vec<Id>,
+    ) -> SubgridSearch<'_, D> {
+        let subgrid_center: Position<D> = self.get_subgrid_center(subgrid_size);
+        let search = SubgridSearch::new(position, subgrid_center, subgrid_size, excluded_ids, self);
+        match self.topology {
+            Topology::Hex => search.hexagonal(position, subgrid_center.x().max(subgrid_center.y())),
+            _ => search,
         }
     }
+
+    /// Number of steps between two cells, moving to neighbouring cells of
+    /// the grid's topology. Diagonal neighbours count as one step on square
+    /// grids, whether agents can step there or not, and the distance
+    /// between levels counts as far as that within a level
+    pub fn distance(&self, a: Position<D>, b: Position<D>) -> usize {
+        match self.topology {
+            Topology::Hex => {
+                let dx = b.x() as i64 - a.x() as i64;
+                let dy = b.y() as i64 - a.y() as i64;
+                let planar = cmp::max(cmp::max(dx.abs(), dy.abs()), (dx + dy).abs()) as usize;
+                let mut level = a;
+                level.0[0] = b.x();
+                level.0[1] = b.y();
+                cmp::max(planar, level.distance(b))
+            }
+            _ => a.distance(b),
+        }
+    }
+
+    pub fn get_subgrid_center(&self, subgrid_size: [usize; D]) -> Position<D> {
+        Position(array::from_fn(|axis| {
+            ((subgrid_size[axis] - 1) as f32 / 2.0).round() as usize
+        }))
+    }
 }
 
-struct SubgridSearch<'a> {
-    x_start: usize,
-    y_start: usize,
-    y_start_: usize,
-    x_end: usize,
-    y_end: usize,
+/// Size of the subgrid taken up by a body of side `body_side`, which is one
+/// level high
+pub fn body_size<const D: usize>(body_side: usize) -> [usize; D] {
+    let mut size = [1; D];
+    size[0] = body_side;
+    size[1] = body_side;
+    size
+}
+
+/// Size of the subgrid reaching as far along every axis, the levels as well,
+/// like the cells within an agent's reach or sight
+pub fn cube_size<const D: usize>(side: usize) -> [usize; D] {
+    [side; D]
+}
+
+/// Strides of the cells of a grid of the given shape in its `val`. Rows are
+/// innermost, followed by the levels and then the columns, so that every
+/// column lies in one piece
+fn strides<const D: usize>(shape: [usize; D]) -> [usize; D] {
+    let mut strides = [1; D];
+    let mut stride: usize = shape[1];
+    for axis in (2..D).chain(iter::once(0)) {
+        strides[axis] = stride;
+        stride *= shape[axis];
+    }
+    strides
+}
+
+/// Terrain of every cell, grass unless covered by a patch
+fn terrain_map(patches: &[TerrainPatch], grid_side: usize) -> Vec<Vec<Terrain>> {
+    let mut terrain: Vec<Vec<Terrain>> = vec![vec![Terrain::Grass; grid_side]; grid_side];
+    for patch in patches {
+        let ((x_start, x_end), (y_start, y_end)) = patch.region.cell_ranges(grid_side);
+        for column in &mut terrain[x_start..x_end] {
+            column[y_start..y_end].fill(patch.terrain);
+        }
+    }
+    terrain
+}
+
+/// Iterator over the ids in a subgrid, except those in `ignore`, going
+/// column by column and, within a column, level by level
+pub struct SubgridSearch<'a, const D: usize = 3> {
+    /// Cell searched next
+    cell: [usize; D],
+    /// Index of the next occupant of the cell to look at
+    k: usize,
+    /// First cell of the subgrid within the grid, and the cell past its
+    /// last one, along every axis
+    start: [usize; D],
+    end: [usize; D],
+    strides: [usize; D],
     ignore: Vec<Id>,
-    val: &'a Vec<Vec<Id>>,
+    val: &'a [Cell],
+    /// On a hex grid, the sum of the axial coordinates of the centre and
+    /// the radius of the hexagon searched
+    hexagon: Option<(i64, i64)>,
 }
 
-impl<'a> SubgridSearch<'a> {
-    fn new(
-        position: Position,
-        subgrid_center: Position,
-        subgrid_size_x: usize,
-        subgrid_size_y: usize,
+impl<'a, const D: usize> SubgridSearch<'a, D> {
+    /// Search of the subgrid placed so that `subgrid_center` lies on `position`
+    pub fn new(
+        position: Position<D>,
+        subgrid_center: Position<D>,
+        subgrid_size: [usize; D],
+        ignore: Vec<Id>,
+        grid: &Grid<D>,
+    ) -> SubgridSearch<'_, D> {
+        let corner: [i64; D] =
+            array::from_fn(|axis| position.0[axis] as i64 - subgrid_center.0[axis] as i64);
+        SubgridSearch::from_corner(corner, subgrid_size, ignore, grid)
+    }
+
+    /// Search of the subgrid with its first cell along every axis at
+    /// `corner`, which may lie outside of the grid
+    fn from_corner(
+        corner: [i64; D],
+        subgrid_size: [usize; D],
         ignore: Vec<Id>,
-        val: &Vec<Vec<Id>>,
-    ) -> SubgridSearch {
-        let x = position.x as i64 - subgrid_center.x as i64;
-        let y = position.y as i64 - subgrid_center.y as i64;
-        let x_start = cmp::max(x, 0) as usize;
-        let y_start = cmp::max(y, 0) as usize;
-        let y_start_ = y_start;
-        let x_end = cmp::min(subgrid_size_x as i64 + x, GRID_SIDE as i64) as usize;
-        let y_end = cmp::min(subgrid_size_y as i64 + y, GRID_SIDE as i64) as usize;
+        grid: &Grid<D>,
+    ) -> SubgridSearch<'_, D> {
+        let start: [usize; D] = array::from_fn(|axis| cmp::max(corner[axis], 0) as usize);
+        let end: [usize; D] = array::from_fn(|axis| {
+            let past = cmp::min(
+                subgrid_size[axis] as i64 + corner[axis],
+                grid.shape[axis] as i64,
+            );
+            cmp::max(past, 0) as usize
+        });
+        let mut cell: [usize; D] = start;
+        // A subgrid which misses the grid along any axis has no cells
+        if (0..D).any(|axis| start[axis] >= end[axis]) {
+            cell[0] = end[0];
+        }
         SubgridSearch {
-            x_start,
-            y_start,
-            y_start_,
-            x_end,
-            y_end,
+            cell,
+            k: 0,
+            start,
+            end,
+            strides: grid.strides,
             ignore,
-            val,
+            val: &grid.val,
+            hexagon: None,
+        }
+    }
+
+    /// Restricts the search to the cells of a hex grid, in axial
+    /// coordinates, within `radius` of `center` on every level
+    pub fn hexagonal(self, center: Position<D>, radius: usize) -> SubgridSearch<'a, D> {
+        SubgridSearch {
+            hexagon: Some(((center.x() + center.y()) as i64, radius as i64)),
+            ..self
+        }
+    }
+
+    /// Rows searched in the column
+    fn column_range(&self, x: usize) -> (usize, usize) {
+        match self.hexagon {
+            None => (self.start[1], self.end[1]),
+            // Cells within the radius also have their coordinate sums
+            // within the radius of the centre's
+            Some((sum, radius)) => {
+                let start = cmp::max(sum - radius - x as i64, self.start[1] as i64) as usize;
+                let end = cmp::min(sum + radius - x as i64 + 1, self.end[1] as i64);
+                (start, cmp::max(end, start as i64) as usize)
+            }
+        }
+    }
+
+    /// Moves on to the first row of the next level of the column, or of the
+    /// next column after its last level
+    fn next_row(&mut self) {
+        self.cell[1] = self.start[1];
+        for axis in (2..D).chain(iter::once(0)) {
+            self.cell[axis] += 1;
+            if axis == 0 || self.cell[axis] < self.end[axis] {
+                return;
+            }
+            self.cell[axis] = self.start[axis];
         }
     }
 }
 
-impl<'a> Iterator for SubgridSearch<'a> {
+impl<'a, const D: usize> Iterator for SubgridSearch<'a, D> {
     type Item = Id;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.x_start >= self.x_end {
-            None
-        } else {
-            if self.y_start != self.y_start_ {
-                let x = self.x_start;
-                for y in self.y_start_..self.y_end {
-                    let val: Id = self.val[x][y];
-                    if val > 0 && !self.ignore.contains(&val) {
-                        self.x_start = if y == self.y_end - 1 { x + 1 } else { x };
-                        self.y_start_ = if y < self.y_end - 1 {
-                            y + 1
-                        } else {
-                            self.y_start
-                        };
-                        return Some(val);
+        while self.cell[0] < self.end[0] {
+            let (y_start, y_end) = self.column_range(self.cell[0]);
+            // Index of the row's first cell
+            let row: usize = (0..D)
+                .filter(|&axis| axis != 1)
+                .map(|axis| self.cell[axis] * self.strides[axis])
+                .sum();
+            self.cell[1] = cmp::max(self.cell[1], y_start);
+            while self.cell[1] < y_end {
+                let ids: &[Id] = &self.val[row + self.cell[1]];
+                while self.k < ids.len() {
+                    let id: Id = ids[self.k];
+                    self.k += 1;
+                    if !self.ignore.contains(&id) {
+                        return Some(id);
                     }
                 }
-                self.x_start += 1;
-                self.y_start_ = self.y_start;
+                self.k = 0;
+                self.cell[1] += 1;
             }
+            self.next_row();
+        }
+        None
+    }
+}
 
-            for x in self.x_start..self.x_end {
-                for y in self.y_start..self.y_end {
-                    let val: Id = self.val[x][y];
-                    if val > 0 && !self.ignore.contains(&val) {
-                        self.x_start = if y == self.y_end - 1 { x + 1 } else { x };
-                        self.y_start_ = if y < self.y_end - 1 {
-                            y + 1
-                        } else {
-                            self.y_start
-                        };
-                        return Some(val);
-                    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use rand::SeedableRng;
+
+    /// Grid with `shape[axis]` cells along every axis and agents
+    /// `Id::test(i)` at the i-th cell
+    fn grid_of_shape<const D: usize>(shape: [usize; D], cells: &[[usize; D]]) -> Grid<D> {
+        let side = shape[0];
+        let mut grid = Grid {
+            val: vec![Cell::new(); shape.iter().product()],
+            shape,
+            strides: strides(shape),
+            topology: Topology::VonNeumann,
+            safe_zones: vec![],
+            terrain: vec![vec![Terrain::Grass; side]; side],
+            obstacles: vec![],
+            doors: vec![],
+            boundary: None,
+            blocked: vec![],
+            allowed: Region::WHOLE,
+        };
+        for (i, &cell) in cells.iter().enumerate() {
+            grid.add(Position(cell), Id::test(i as u32));
+        }
+        grid
+    }
+
+    /// Grid of the given side with agents `Id::test(i)` at the i-th position
+    fn grid_with(side: usize, positions: &[(usize, usize)]) -> Grid {
+        let cells: Vec<[usize; 3]> = positions.iter().map(|&(x, y)| [x, y, 0]).collect();
+        grid_of_shape([side, side, 1], &cells)
+    }
+
+    /// Grid with `levels` levels and agents `Id::test(i)` at the i-th position
+    fn grid_3d_with(side: usize, levels: usize, positions: &[(usize, usize, usize)]) -> Grid {
+        let cells: Vec<[usize; 3]> = positions.iter().map(|&(x, y, z)| [x, y, z]).collect();
+        grid_of_shape([side, side, levels], &cells)
+    }
+
+    fn pos(x: usize, y: usize) -> Position {
+        Position::new(x, y, 0)
+    }
+
+    fn pos3(x: usize, y: usize, z: usize) -> Position {
+        Position::new(x, y, z)
+    }
+
+    fn hex_grid_with(side: usize, positions: &[(usize, usize)]) -> Grid {
+        Grid {
+            topology: Topology::Hex,
+            ..grid_with(side, positions)
+        }
+    }
+
+    /// Occupiers of the subgrid found by checking every cell of the grid,
+    /// which `Grid::cells` yields column by column and level by level like
+    /// `SubgridSearch`. On a hex grid, cells further from the position
+    /// within their level than the subgrid's centre are left out
+    fn reference_occupiers<const D: usize>(
+        grid: &Grid<D>,
+        position: Position<D>,
+        size: [usize; D],
+        excluded_ids: &[Id],
+        maybe_excluded_fn: Option<&dyn Fn(Id) -> bool>,
+    ) -> Vec<Id> {
+        let center = grid.get_subgrid_center(size);
+        let mut ids = vec![];
+        for (cell, occupants) in grid.cells() {
+            let in_subgrid = (0..D).all(|axis| {
+                let start = position.0[axis] as i64 - center.0[axis] as i64;
+                (start..start + size[axis] as i64).contains(&(cell.0[axis] as i64))
+            });
+            let mut planar = position;
+            planar.0[0] = cell.x();
+            planar.0[1] = cell.y();
+            if !in_subgrid
+                || grid.topology == Topology::Hex
+                    && grid.distance(position, planar) > center.x().max(center.y())
+            {
+                continue;
+            }
+            for &id in occupants {
+                if !excluded_ids.contains(&id) && !maybe_excluded_fn.is_some_and(|f| f(id)) {
+                    ids.push(id);
                 }
             }
-            self.x_start = self.x_end;
-            None
         }
+        ids
+    }
+
+    #[test]
+    fn subgrid_center_rounds_up_for_even_sizes() {
+        let grid = grid_with(5, &[]);
+        assert_eq!(grid.get_subgrid_center([1, 1, 1]), pos(0, 0));
+        assert_eq!(grid.get_subgrid_center([3, 3, 1]), pos(1, 1));
+        assert_eq!(grid.get_subgrid_center([4, 2, 1]), pos(2, 1));
+        assert_eq!(grid.get_subgrid_center([3, 3, 4]).z(), 2);
+    }
+
+    #[test]
+    fn even_subgrid_reaches_further_back() {
+        // A 4x4 subgrid around (2, 2) covers cells 0..=3 on both axes
+        let grid = grid_with(5, &[(0, 0), (3, 3), (4, 2)]);
+        let ids = grid.get_subgrid_occupiers(pos(2, 2), [4, 4, 1], vec![], None);
+        assert_eq!(ids, vec![Id::test(0), Id::test(1)]);
+    }
+
+    #[test]
+    fn subgrid_is_clamped_at_borders() {
+        let grid = grid_with(4, &[(0, 0), (3, 3), (0, 3)]);
+        let ids = grid.get_subgrid_occupiers(pos(0, 0), [3, 3, 1], vec![], None);
+        assert_eq!(ids, vec![Id::test(0)]);
+        let ids = grid.get_subgrid_occupiers(pos(3, 3), [3, 3, 1], vec![], None);
+        assert_eq!(ids, vec![Id::test(1)]);
+        let ids = grid.get_subgrid_occupiers(pos(0, 3), [9, 1, 1], vec![], None);
+        assert_eq!(ids, vec![Id::test(2), Id::test(1)]);
+    }
+
+    #[test]
+    fn search_resumes_within_a_column() {
+        // Several agents in one column and the next, so that the search
+        // has to continue both mid-column and at the end of a column
+        let grid = grid_with(5, &[(1, 0), (1, 2), (1, 4), (2, 1), (2, 4), (3, 0)]);
+        let search = SubgridSearch::new(pos(2, 2), pos(2, 2), [5, 5, 1], vec![], &grid);
+        let ids: Vec<Id> = search.collect();
+        assert_eq!(ids, (0..6).map(Id::test).collect::<Vec<Id>>());
+    }
+
+    #[test]
+    fn excluded_ids_and_predicate_are_skipped() {
+        let grid = grid_with(3, &[(0, 0), (1, 1), (2, 2)]);
+        let is_first = |id: Id| id == Id::test(0);
+        let ids =
+            grid.get_subgrid_occupiers(pos(1, 1), [3, 3, 1], vec![Id::test(1)], Some(&is_first));
+        assert_eq!(ids, vec![Id::test(2)]);
+        assert!(!grid.is_subgrid_free(pos(1, 1), [3, 3, 1], vec![Id::test(1)], Some(&is_first)));
+        let ignore_all = |_: Id| true;
+        assert!(grid.is_subgrid_free(pos(1, 1), [3, 3, 1], vec![], Some(&ignore_all)));
+        assert!(!grid.is_subgrid_occupied(pos(1, 1), [3, 3, 1], vec![], Some(&ignore_all)));
+    }
+
+    #[test]
+    fn search_of_empty_grid_finds_nothing() {
+        let grid = grid_with(3, &[]);
+        assert!(grid.is_subgrid_free(pos(1, 1), [3, 3, 1], vec![], None));
+        assert!(!grid.is_subgrid_occupied(pos(1, 1), [3, 3, 1], vec![], None));
+        assert!(grid
+            .get_subgrid_occupiers(pos(0, 2), [8, 8, 1], vec![], None)
+            .is_empty());
+    }
+
+    #[test]
+    fn points_move_along_heading_and_stay_in_grid() {
+        let point = Point {
+            x: 2.5,
+            y: 2.5,
+            z: 0.0,
+        };
+        let moved = point.moved(std::f32::consts::FRAC_PI_2, 1.0, 4).unwrap();
+        assert!((moved.x - 2.5).abs() < 1e-6 && (moved.y - 3.5).abs() < 1e-6);
+        assert_eq!(moved.cell(), pos(2, 3));
+        assert!(point.moved(0.0, 1.5, 4).is_none());
+        assert!(point.moved(std::f32::consts::PI, 3.0, 4).is_none());
+    }
+
+    #[test]
+    fn hex_subgrid_is_a_hexagon() {
+        let cells: Vec<(usize, usize)> = (0..5).flat_map(|x| (0..5).map(move |y| (x, y))).collect();
+        let grid = hex_grid_with(5, &cells);
+        let ids = grid.get_subgrid_occupiers(pos(2, 2), [3, 3, 1], vec![], None);
+        // The neighbours of (2, 2) and the cell itself, in column order
+        let expected: Vec<Id> = [(1, 2), (1, 3), (2, 1), (2, 2), (2, 3), (3, 1), (3, 2)]
+            .iter()
+            .map(|&(x, y)| Id::test(x * 5 + y))
+            .collect();
+        assert_eq!(ids, expected);
+        assert_eq!(grid.distance(pos(0, 4), pos(4, 0)), 4);
+        assert_eq!(grid.distance(pos(0, 0), pos(4, 4)), 8);
+    }
+
+    #[test]
+    fn diagonal_paths_are_checked_cell_by_cell() {
+        // An agent next to the diagonal blocks a body of side 3 moving along
+        // it, but not one of side 1
+        let grid = grid_with(6, &[(3, 2)]);
+        assert!(!grid.is_path_free(pos(0, 0), (1, 1), 3, 3, vec![]));
+        assert!(grid.is_path_free(pos(0, 0), (1, 1), 3, 1, vec![]));
+        assert!(!grid.is_path_free(pos(0, 0), (1, 1), 6, 1, vec![]));
+    }
+
+    #[test]
+    fn agents_can_share_a_cell() {
+        let mut grid = grid_with(3, &[(1, 1), (1, 1), (2, 2)]);
+        let ids = grid.get_subgrid_occupiers(pos(1, 1), [1, 1, 1], vec![], None);
+        assert_eq!(ids, vec![Id::test(0), Id::test(1)]);
+        // Moving one of them away leaves the other in place
+        grid.update(vec![PositionChange {
+            id: Id::test(0),
+            before: pos(1, 1),
+            after: pos(2, 2),
+        }]);
+        let ids = grid.get_subgrid_occupiers(pos(1, 1), [3, 3, 1], vec![], None);
+        assert_eq!(ids, vec![Id::test(1), Id::test(2), Id::test(0)]);
+        assert!(!grid.is_subgrid_free(pos(1, 1), [1, 1, 1], vec![Id::test(0)], None));
+    }
+
+    #[test]
+    fn moves_into_a_cell_being_left_keep_both_agents() {
+        // The second agent moves into the cell the first one leaves, in
+        // either order, and neither may be erased
+        for &order in &[[0, 1], [1, 0]] {
+            let mut grid = grid_with(3, &[(0, 0), (1, 0)]);
+            let changes = [
+                PositionChange {
+                    id: Id::test(0),
+                    before: pos(0, 0),
+                    after: pos(0, 1),
+                },
+                PositionChange {
+                    id: Id::test(1),
+                    before: pos(1, 0),
+                    after: pos(0, 0),
+                },
+            ];
+            grid.update(order.iter().map(|&i| changes[i]).collect());
+            assert_eq!(grid.cell(pos(0, 0)).to_vec(), vec![Id::test(1)]);
+            assert_eq!(grid.cell(pos(0, 1)).to_vec(), vec![Id::test(0)]);
+            assert!(grid.cell(pos(1, 0)).is_empty());
+        }
+    }
+
+    #[test]
+    fn bodies_take_up_one_level_and_reach_spans_levels() {
+        let mut grid = grid_3d_with(3, 3, &[(1, 1, 0), (1, 1, 2), (0, 0, 1)]);
+        assert_eq!(grid.levels(), 3);
+        // Agents right above and below a body are not in its way
+        let ids = grid.get_subgrid_occupiers(pos3(1, 1, 1), body_size(3), vec![], None);
+        assert_eq!(ids, vec![Id::test(2)]);
+        assert!(grid.is_path_free(pos3(0, 1, 1), (1, 0), 2, 1, vec![]));
+        // but within its reach, column by column and level by level
+        let ids = grid.get_subgrid_occupiers(pos3(1, 1, 1), cube_size(3), vec![], None);
+        assert_eq!(ids, vec![Id::test(2), Id::test(0), Id::test(1)]);
+        let ids = grid.get_subgrid_occupiers(pos3(1, 1, 0), cube_size(3), vec![], None);
+        assert_eq!(ids, vec![Id::test(2), Id::test(0)]);
+        // A climb onto an agent is blocked, one next to it with a wide body
+        let climbed = pos3(1, 1, 1).climbed(1, 3).unwrap();
+        assert!(!grid.is_subgrid_free(climbed, body_size(1), vec![], None));
+        let climbed = pos3(0, 1, 0).climbed(1, 3).unwrap();
+        assert!(grid.is_subgrid_free(climbed, body_size(1), vec![], None));
+        assert!(!grid.is_subgrid_free(climbed, body_size(3), vec![], None));
+        assert!(pos3(1, 1, 2).climbed(1, 3).is_none());
+        assert_eq!(pos3(0, 0, 0).distance(pos3(1, 0, 2)), 2);
+        grid.update(vec![PositionChange {
+            id: Id::test(1),
+            before: pos3(1, 1, 2),
+            after: pos3(1, 1, 1),
+        }]);
+        assert_eq!(grid.cell(pos3(1, 1, 1)).to_vec(), vec![Id::test(1)]);
+        assert!(grid.cell(pos3(1, 1, 2)).is_empty());
+    }
+
+    #[test]
+    fn grids_may_have_any_number_of_axes() {
+        // A flat grid of two axes
+        let grid = grid_of_shape([4, 4], &[[0, 0], [1, 2], [3, 3]]);
+        let ids = grid.get_subgrid_occupiers(Position([1, 1]), [3, 3], vec![], None);
+        assert_eq!(ids, vec![Id::test(0), Id::test(1)]);
+        assert_eq!(grid.levels(), 1);
+        // and one with levels along two further axes, searched level by level
+        // along the third axis first
+        let cells = [[1, 1, 0, 0], [1, 1, 1, 0], [1, 1, 0, 1], [2, 2, 1, 1]];
+        let mut grid = grid_of_shape([3, 3, 2, 2], &cells);
+        assert_eq!(grid.levels(), 4);
+        let position = Position([1, 1, 0, 0]);
+        let ids = grid.get_subgrid_occupiers(position, cube_size(3), vec![], None);
+        assert_eq!(ids, (0..4).map(Id::test).collect::<Vec<Id>>());
+        let ids = grid.get_subgrid_occupiers(position, body_size(3), vec![], None);
+        assert_eq!(ids, vec![Id::test(0)]);
+        assert_eq!(position.distance(Position([2, 2, 1, 1])), 1);
+        let moved = position.moved_along(3, 1, 2).unwrap();
+        grid.update(vec![PositionChange {
+            id: Id::test(0),
+            before: position,
+            after: moved,
+        }]);
+        assert_eq!(grid.cell(moved).to_vec(), vec![Id::test(2), Id::test(0)]);
+        let cells: Vec<Position<4>> = grid
+            .cells()
+            .filter(|(_, ids)| !ids.is_empty())
+            .map(|(cell, _)| cell)
+            .collect();
+        assert_eq!(
+            cells,
+            [[1, 1, 1, 0], [1, 1, 0, 1], [2, 2, 1, 1]].map(Position)
+        );
+    }
+
+    /// Side of a grid from 1 to 12, up to 40 cells of it holding agents,
+    /// which may hold several, and a position in it
+    fn layout() -> impl Strategy<Value = (usize, Vec<(usize, usize)>, Position)> {
+        (1usize..=12).prop_flat_map(|side| {
+            (
+                prop::collection::vec((0..side, 0..side), 0..=40),
+                0..side,
+                0..side,
+            )
+                .prop_map(move |(cells, x, y)| (side, cells, pos(x, y)))
+        })
+    }
+
+    /// Like `layout`, in a grid of 1 to 4 levels
+    fn layout_3d() -> impl Strategy<Value = (usize, usize, Vec<(usize, usize, usize)>, Position)> {
+        (1usize..=8, 1usize..=4).prop_flat_map(|(side, levels)| {
+            (
+                prop::collection::vec((0..side, 0..side, 0..levels), 0..=40),
+                (0..side, 0..side, 0..levels),
+            )
+                .prop_map(move |(cells, (x, y, z))| (side, levels, cells, pos3(x, y, z)))
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn subgrid_queries_match_reference(
+            (side, cells, position) in layout(),
+            size_x in 1usize..=9,
+            size_y in 1usize..=9,
+            excluded in prop::collection::vec(0u32..40, 0..4),
+            divisor in prop::option::of(2u32..5),
+        ) {
+            let grid = grid_with(side, &cells);
+            let size = [size_x, size_y, 1];
+            let excluded: Vec<Id> = excluded.into_iter().map(Id::test).collect();
+            // Excludes agents by a rule which depends on the id, like the
+            // state checks of the actions
+            let predicate = move |id: Id| {
+                divisor.is_some_and(|d| (0..40).step_by(d as usize).any(|i| Id::test(i) == id))
+            };
+            let maybe_predicate: Option<&dyn Fn(Id) -> bool> = match divisor {
+                Some(_) => Some(&predicate),
+                None => None,
+            };
+
+            let expected = reference_occupiers(&grid, position, size, &excluded, maybe_predicate);
+            let occupiers =
+                grid.get_subgrid_occupiers(position, size, excluded.clone(), maybe_predicate);
+            prop_assert_eq!(&occupiers, &expected);
+            prop_assert_eq!(
+                grid.is_subgrid_free(position, size, excluded.clone(), maybe_predicate),
+                expected.is_empty()
+            );
+            prop_assert_eq!(
+                grid.is_subgrid_occupied(position, size, excluded, maybe_predicate),
+                !expected.is_empty()
+            );
+        }
+
+        #[test]
+        fn hex_subgrid_queries_match_reference(
+            (side, cells, position) in layout(),
+            size in 1usize..=9,
+            excluded in prop::collection::vec(0u32..40, 0..4),
+        ) {
+            let grid = hex_grid_with(side, &cells);
+            let size = [size, size, 1];
+            let excluded: Vec<Id> = excluded.into_iter().map(Id::test).collect();
+            let expected = reference_occupiers(&grid, position, size, &excluded, None);
+            let occupiers = grid.get_subgrid_occupiers(position, size, excluded.clone(), None);
+            prop_assert_eq!(&occupiers, &expected);
+            prop_assert_eq!(
+                grid.is_subgrid_free(position, size, excluded, None),
+                expected.is_empty()
+            );
+        }
+
+        #[test]
+        fn subgrid_search_can_be_resumed(
+            (side, cells, position) in layout(),
+            size_x in 1usize..=9,
+            size_y in 1usize..=9,
+            chunk in 1usize..4,
+        ) {
+            let grid = grid_with(side, &cells);
+            // Taking a few ids at a time, so that the search stops and
+            // resumes at every position, yields the same ids as the reference
+            let size = [size_x, size_y, 1];
+            let center = grid.get_subgrid_center(size);
+            let mut search = SubgridSearch::new(position, center, size, vec![], &grid);
+            let mut ids = vec![];
+            loop {
+                let taken: Vec<Id> = search.by_ref().take(chunk).collect();
+                if taken.is_empty() {
+                    break;
+                }
+                ids.extend(taken);
+            }
+            prop_assert!(search.next().is_none());
+            let expected = reference_occupiers(&grid, position, size, &[], None);
+            prop_assert_eq!(ids, expected);
+        }
+
+        #[test]
+        fn subgrid_queries_match_reference_in_3d(
+            (side, levels, cells, position) in layout_3d(),
+            size_x in 1usize..=7,
+            size_y in 1usize..=7,
+            size_z in 1usize..=4,
+            excluded in prop::collection::vec(0u32..40, 0..4),
+            hex in any::<bool>(),
+        ) {
+            let grid = Grid {
+                topology: if hex { Topology::Hex } else { Topology::VonNeumann },
+                ..grid_3d_with(side, levels, &cells)
+            };
+            let size = [size_x, size_y, size_z];
+            let excluded: Vec<Id> = excluded.into_iter().map(Id::test).collect();
+            let expected = reference_occupiers(&grid, position, size, &excluded, None);
+            let occupiers = grid.get_subgrid_occupiers(position, size, excluded.clone(), None);
+            prop_assert_eq!(&occupiers, &expected);
+            prop_assert_eq!(
+                grid.is_subgrid_free(position, size, excluded, None),
+                expected.is_empty()
+            );
+        }
+    }
+
+    #[test]
+    fn later_terrain_patches_lie_on_top() {
+        let patches = [
+            TerrainPatch {
+                terrain: Terrain::Sand,
+                region: Region {
+                    x: (0.0, 0.5),
+                    y: (0.0, 1.0),
+                },
+            },
+            TerrainPatch {
+                terrain: Terrain::Water,
+                region: Region {
+                    x: (0.25, 0.75),
+                    y: (0.5, 1.0),
+                },
+            },
+        ];
+        let terrain = terrain_map(&patches, 4);
+        assert_eq!(terrain[0], vec![Terrain::Sand; 4]);
+        assert_eq!(
+            terrain[1],
+            vec![Terrain::Sand, Terrain::Sand, Terrain::Water, Terrain::Water]
+        );
+        assert_eq!(
+            terrain[2],
+            vec![
+                Terrain::Grass,
+                Terrain::Grass,
+                Terrain::Water,
+                Terrain::Water
+            ]
+        );
+        assert_eq!(terrain[3], vec![Terrain::Grass; 4]);
+    }
+
+    #[test]
+    fn regions_off_the_grid_cover_no_cells() {
+        let region = |x: (f32, f32)| Region { x, y: (0.0, 1.0) };
+        assert_eq!(region((0.25, 0.5)).cell_ranges(4), ((1, 2), (0, 4)));
+        assert_eq!(region((0.5, 0.5)).cell_ranges(4), ((2, 3), (0, 4)));
+        assert_eq!(region((-0.5, 0.25)).cell_ranges(4), ((0, 1), (0, 4)));
+        assert_eq!(region((1.0, 1.5)).cell_ranges(4).0, (0, 0));
+        assert_eq!(region((-0.5, 0.0)).cell_ranges(4).0, (0, 0));
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(region((1.25, 1.5))
+            .random_position::<3>(4, &mut rng)
+            .is_none());
+        assert!(!region((1.25, 1.5)).contains(pos(3, 0), 4));
+        assert_eq!(region((1.25, 1.5)).distance(pos(3, 0), 4), None);
+        assert_eq!(region((0.25, 0.5)).distance(pos(3, 0), 4), Some(2));
+        assert_eq!(region((0.25, 0.5)).distance(pos(1, 3), 4), Some(0));
+    }
+
+    #[test]
+    fn environment_changes_with_the_steps() {
+        let region = Region {
+            x: (0.0, 0.25),
+            y: (0.0, 0.25),
+        };
+        let mut grid = grid_with(8, &[]);
+        grid.obstacles = vec![Obstacle {
+            region,
+            path: &[(0.5, 0.0)],
+            steps_per_leg: 4,
+        }];
+        grid.doors = vec![Door {
+            region: region.shifted(0.0, 0.5),
+            open_steps: 2,
+            closed_steps: 3,
+        }];
+        grid.boundary = Some(Boundary {
+            start_step: 2,
+            end_step: 6,
+            final_region: Region {
+                x: (0.5, 1.0),
+                y: (0.0, 1.0),
+            },
+        });
+        // The obstacle starts in the corner, is halfway along its path after
+        // 4 steps and back after 8. The door is open at first
+        grid.advance(0);
+        assert!(grid.is_blocked(pos(1, 1)) && !grid.is_blocked(pos(1, 5)));
+        assert!(grid.is_allowed(pos(0, 0)));
+        grid.advance(4);
+        assert!(!grid.is_blocked(pos(1, 1)) && grid.is_blocked(pos(5, 1)));
+        assert!(grid.is_blocked(pos(1, 5)));
+        // Halfway through shrinking, the boundary has moved a quarter in
+        assert!(!grid.is_allowed(pos(1, 0)) && grid.is_allowed(pos(2, 0)));
+        // Agents can not move out of the boundary, but can head back in
+        assert!(!grid.can_pass(pos(2, 7), pos(1, 7)));
+        assert!(grid.can_pass(pos(0, 7), pos(1, 7)));
+        assert!(!grid.can_pass(pos(4, 0), pos(5, 1)));
+        grid.advance(8);
+        assert!(grid.is_blocked(pos(1, 1)) && !grid.is_blocked(pos(5, 1)));
+        assert!(!grid.is_allowed(pos(3, 0)) && grid.is_allowed(pos(4, 0)));
     }
 }
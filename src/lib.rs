@@ -0,0 +1,118 @@
+extern crate piston;
+extern crate rand;
+extern crate rayon;
+extern crate smallvec;
+
+pub mod action;
+pub mod agent;
+pub mod config;
+pub mod display;
+pub mod engine;
+pub mod grid;
+pub mod state;
+pub mod validation;
+
+use config::{
+    AgentType, Boundary, Door, GameMode, Obstacle, OutsideRule, Schedule, Space, SpawnPoint,
+    TagBackRules, Team, TerrainEffect, TerrainEffects, TerrainPatch, Topology,
+};
+use grid::Region;
+
+pub const WINDOW_SIDE: f64 = 720.0;
+pub const LAST_UNTAGGED_DISPLAY_LENGTH: usize = 100;
+
+
+//         Modify these values             //
+pub const USE_VIEWER: bool = true;
+pub const VIEW_LEVEL: Option<usize> = None;
+pub const GAME_MODE: GameMode = GameMode::Classic;
+pub const SCHEDULE: Schedule = Schedule::Synchronous;
+pub const SPACE: Space = Space::Discrete;
+pub const STEER_ANGLE: f32 = 30.0;
+pub const TOPOLOGY: Topology = Topology::VonNeumann;
+pub const INFECTION_RECOVERY_STEPS: Option<usize> = None;
+pub const INFECTION_CURVE_PATH: Option<&str> = Some("infection_curve.csv");
+pub const TEAMS: &[Team] = &[
+    Team {
+        name: "red",
+        size: 600,
+        region: Region {
+            x: (0.0, 0.3),
+            y: (0.0, 1.0),
+        },
+    },
+    Team {
+        name: "green",
+        size: 600,
+        region: Region {
+            x: (0.7, 1.0),
+            y: (0.0, 1.0),
+        },
+    },
+    Team {
+        name: "blue",
+        size: 600,
+        region: Region {
+            x: (0.3, 0.7),
+            y: (0.0, 0.4),
+        },
+    },
+];
+pub const SAFE_ZONES: &[Region] = &[];
+pub const SAFE_ZONE_STAY_LIMIT: Option<usize> = None;
+pub const TERRAIN: &[TerrainPatch] = &[];
+pub const TERRAIN_EFFECTS: TerrainEffects = TerrainEffects {
+    grass: TerrainEffect {
+        move_probability: 1.0,
+        speed_factor: 1.0,
+    },
+    sand: TerrainEffect {
+        move_probability: 0.8,
+        speed_factor: 0.75,
+    },
+    mud: TerrainEffect {
+        move_probability: 0.5,
+        speed_factor: 0.5,
+    },
+    water: TerrainEffect {
+        move_probability: 0.3,
+        speed_factor: 0.5,
+    },
+};
+pub const OBSTACLES: &[Obstacle] = &[];
+pub const DOORS: &[Door] = &[];
+pub const BOUNDARY: Option<Boundary> = None;
+pub const OUTSIDE_RULE: OutsideRule = OutsideRule::Teleport;
+pub const COLLSION_DETECTION: bool = true;
+pub const VALIDATE: bool = false;
+pub const SEED: Option<u64> = None;
+pub const GRID_SIDE: usize = 1000;
+pub const GRID_LEVELS: usize = 1;
+pub const NUM_ROUNDS: usize = 1;
+pub const NUM_STEPS: usize = 20000;
+pub const NUM_AGENTS: usize = 2000;
+pub const NUM_AGENTS_IT: usize = 2;
+pub const SPAWN_POINTS: &[SpawnPoint] = &[];
+pub const MAX_STAMINA: f32 = 100.0;
+pub const STEP_STAMINA_COST: f32 = 0.0;
+pub const REST_STAMINA_GAIN: f32 = 0.0;
+pub const TAG_COOLDOWN: usize = 0;
+pub const TAG_BACK_RULES: TagBackRules = TagBackRules {
+    no_tag_back_steps: usize::MAX,
+    retag_window: 0,
+    immunity_steps: 0,
+    history_length: 1,
+};
+pub const AGENT_TYPES: &[AgentType] = &[AgentType {
+    name: "player",
+    share: 1.0,
+    body_side: (21, 21),
+    tag_reach: (31, 31),
+    speed: (1, 1),
+    vision_radius: (40, 40),
+    impassable: &[],
+}];
+pub const IT_SPEED_BONUS: usize = 0;
+pub const SPRINT_STAMINA_COST: f32 = 10.0;
+pub const SPRINT_DURATION: usize = 1;
+//    More information in ../README.md     //
@@ -1,49 +1,25 @@
-extern crate piston;
-extern crate rand;
-extern crate rayon;
+extern crate tag;
 
-mod action;
-mod agent;
-mod display;
-mod engine;
-mod grid;
-
-use action::*;
-use agent::AgentManager;
-use engine::Engine;
-use grid::Grid;
+use tag::action::ActionContext;
+use tag::agent::AgentManager;
+use tag::config::Config;
+use tag::engine::Engine;
+use tag::grid::Grid;
+use tag::USE_VIEWER;
 
 use std::time::Instant;
 
-pub const WINDOW_SIDE: f64 = 720.0;
-pub const AGENT_NUM_UPPER_BOUND: usize = GRID_SIDE * GRID_SIDE / (STEP_SG_SIDE / STEP_SG_SIDE);
-pub const BODY_PIXEL_SIZE: usize = 15 * WINDOW_SIDE as usize / GRID_SIDE;
-pub const LAST_UNTAGGED_DISPLAY_LENGTH: usize = 100;
-
-
-//         Modify these values             //
-pub const USE_VIEWER: bool = true;
-pub const COLLSION_DETECTION: bool = true;
-pub const STEP_SG_SIDE: usize = 21;
-pub const TAG_SG_SIDE: usize = 31;
-pub const GRID_SIDE: usize = 1000;
-pub const NUM_STEPS: usize = 20000;
-pub const NUM_AGENTS: usize = 2000;
-pub const NUM_AGENTS_IT: usize = 2;
-//    More information in ../README.md     //
-
 fn main() {
     let now = Instant::now();
 
-    let ac: ActionContext = ActionContext::new();
-    let mut grid: Grid = Grid {
-        val: vec![vec![0; GRID_SIDE]; GRID_SIDE],
-    };
-    let am: AgentManager = AgentManager::new(&ac, &mut grid, NUM_AGENTS, NUM_AGENTS_IT);
+    let config: Config = Config::default();
+    let ac: ActionContext = ActionContext::new(&config);
+    let mut grid: Grid = Grid::new(&config);
+    let am: AgentManager = AgentManager::new(&config, &ac, &mut grid);
 
-    let mut engine: Engine = Engine::new(grid, ac, am, USE_VIEWER);
+    let mut engine: Engine = Engine::new(&config, grid, ac, am, USE_VIEWER);
 
-    for _ in 0..NUM_STEPS {
+    while !engine.is_finished() {
         engine.step();
     }
     println!("Took {} ms", now.elapsed().as_millis());
@@ -0,0 +1,219 @@
+use crate::config::GameMode;
+
+/// State of an agent in the game, which decides what it can do and what
+/// can be done to it
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum State {
+    Runner,
+    It,
+    Frozen,
+    Recovered,
+    /// Left the field, e.g. after being tagged in elimination mode
+    Eliminated,
+}
+
+/// Events which change the state of an agent, according to the transitions
+/// of the game's rules
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Trigger {
+    /// The agent tagged someone
+    Tag,
+    /// The agent was tagged
+    Tagged,
+    /// The agent was released (e.g. unfrozen) by a teammate
+    Released,
+    /// The agent has been in its current state for the given number of steps
+    After(usize),
+}
+
+#[derive(Copy, Clone)]
+pub struct Transition {
+    pub from: State,
+    pub trigger: Trigger,
+    pub to: State,
+}
+
+/// Rules of a game variant, given as the abilities of agents in every state
+/// and a table of transitions between states
+#[derive(Clone)]
+pub struct Rules {
+    /// States in which agents can move
+    pub moving: Vec<State>,
+    /// States in which agents can tag others
+    pub tagging: Vec<State>,
+    /// States in which agents can release others
+    pub releasing: Vec<State>,
+    /// Whether agents can only tag members of other teams, who are captured
+    /// by the tagger's team
+    pub capture: bool,
+    /// An agent can only be tagged or released if there is a transition
+    /// for it from its current state
+    pub transitions: Vec<Transition>,
+}
+
+impl Rules {
+    /// Rules of the built-in game modes. `recovery_steps` is only used in
+    /// infection mode
+    pub fn new(game_mode: GameMode, recovery_steps: Option<usize>) -> Rules {
+        use State::*;
+        use Trigger::*;
+        let t = |from, trigger, to| Transition { from, trigger, to };
+        match game_mode {
+            GameMode::Classic => Rules {
+                moving: vec![Runner, It],
+                tagging: vec![It],
+                releasing: vec![],
+                capture: false,
+                transitions: vec![t(Runner, Tagged, It), t(It, Tag, Runner)],
+            },
+            GameMode::Freeze => Rules {
+                moving: vec![Runner, It],
+                tagging: vec![It],
+                releasing: vec![Runner],
+                capture: false,
+                transitions: vec![t(Runner, Tagged, Frozen), t(Frozen, Released, Runner)],
+            },
+            GameMode::Infection => {
+                let mut transitions = vec![t(Runner, Tagged, It)];
+                if let Some(steps) = recovery_steps {
+                    transitions.push(t(It, After(steps), Recovered));
+                }
+                Rules {
+                    moving: vec![Runner, It, Recovered],
+                    tagging: vec![It],
+                    releasing: vec![],
+                    capture: false,
+                    transitions,
+                }
+            }
+            GameMode::Elimination => Rules {
+                moving: vec![Runner, It],
+                tagging: vec![It],
+                releasing: vec![],
+                capture: false,
+                transitions: vec![t(Runner, Tagged, Eliminated)],
+            },
+            // Captured agents change team, but stay runners
+            GameMode::Team => Rules {
+                moving: vec![Runner],
+                tagging: vec![Runner],
+                releasing: vec![],
+                capture: true,
+                transitions: vec![t(Runner, Tagged, Runner)],
+            },
+        }
+    }
+
+    /// State after the trigger, if there is a transition for it
+    pub fn next(&self, state: State, trigger: Trigger) -> Option<State> {
+        self.transitions
+            .iter()
+            .find(|t| t.from == state && t.trigger == trigger)
+            .map(|t| t.to)
+    }
+
+    /// State an agent moves to once it has been in `state` for `steps`
+    /// steps, if there is a timed transition which has run out
+    pub fn next_after(&self, state: State, steps: usize) -> Option<State> {
+        self.transitions.iter().find_map(|t| match t.trigger {
+            Trigger::After(after) if t.from == state && steps >= after => Some(t.to),
+            _ => None,
+        })
+    }
+
+    pub fn can_move(&self, state: State) -> bool {
+        self.moving.contains(&state)
+    }
+
+    pub fn can_tag(&self, state: State) -> bool {
+        self.tagging.contains(&state)
+    }
+
+    pub fn can_release(&self, state: State) -> bool {
+        self.releasing.contains(&state)
+    }
+
+    pub fn can_be_tagged(&self, state: State) -> bool {
+        self.next(state, Trigger::Tagged).is_some()
+    }
+
+    pub fn can_be_released(&self, state: State) -> bool {
+        self.next(state, Trigger::Released).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use State::*;
+    use Trigger::*;
+
+    #[test]
+    fn classic_and_freeze_tag_pass_on_and_release() {
+        let classic = Rules::new(GameMode::Classic, None);
+        assert_eq!(classic.next(Runner, Tagged), Some(It));
+        assert_eq!(classic.next(It, Tag), Some(Runner));
+        assert_eq!(classic.next(It, Tagged), None);
+        assert_eq!(classic.next(Runner, Released), None);
+        assert!(classic.can_be_tagged(Runner) && !classic.can_be_tagged(It));
+
+        let freeze = Rules::new(GameMode::Freeze, None);
+        assert_eq!(freeze.next(Runner, Tagged), Some(Frozen));
+        assert_eq!(freeze.next(It, Tag), None);
+        assert_eq!(freeze.next(Frozen, Released), Some(Runner));
+        assert!(!freeze.can_move(Frozen) && !freeze.can_be_tagged(Frozen));
+        assert!(freeze.can_release(Runner) && freeze.can_be_released(Frozen));
+        assert!(!freeze.can_be_released(Runner));
+    }
+
+    #[test]
+    fn infected_agents_recover_only_with_recovery_steps() {
+        let infection = Rules::new(GameMode::Infection, Some(5));
+        assert_eq!(infection.next(Runner, Tagged), Some(It));
+        assert_eq!(infection.next(It, Tag), None);
+        assert_eq!(infection.next_after(It, 4), None);
+        assert_eq!(infection.next_after(It, 5), Some(Recovered));
+        assert_eq!(infection.next_after(Runner, 5), None);
+        assert!(infection.can_move(Recovered) && !infection.can_be_tagged(Recovered));
+
+        let lasting = Rules::new(GameMode::Infection, None);
+        assert_eq!(lasting.next_after(It, usize::MAX), None);
+    }
+
+    #[test]
+    fn tagged_agents_leave_or_change_team() {
+        let elimination = Rules::new(GameMode::Elimination, None);
+        assert_eq!(elimination.next(Runner, Tagged), Some(Eliminated));
+        assert!(!elimination.can_move(Eliminated) && !elimination.can_be_tagged(Eliminated));
+
+        // Captured runners stay runners, and every runner can tag
+        let team = Rules::new(GameMode::Team, None);
+        assert!(team.capture);
+        assert_eq!(team.next(Runner, Tagged), Some(Runner));
+        assert!(team.can_tag(Runner) && team.can_be_tagged(Runner));
+    }
+
+    #[test]
+    fn custom_tables_define_new_variants() {
+        // Freeze tag in which frozen runners thaw on their own after 3 steps
+        // and 'it' agents can be tagged back into runners
+        let t = |from, trigger, to| Transition { from, trigger, to };
+        let rules = Rules {
+            moving: vec![Runner, It],
+            tagging: vec![It, Runner],
+            releasing: vec![],
+            capture: false,
+            transitions: vec![
+                t(Runner, Tagged, Frozen),
+                t(Frozen, After(3), Runner),
+                t(It, Tagged, Runner),
+            ],
+        };
+        assert!(rules.can_be_tagged(It) && !rules.can_be_tagged(Frozen));
+        assert_eq!(rules.next(It, Tagged), Some(Runner));
+        assert_eq!(rules.next(Frozen, Released), None);
+        assert_eq!(rules.next_after(Frozen, 2), None);
+        assert_eq!(rules.next_after(Frozen, 3), Some(Runner));
+        assert_eq!(rules.next_after(It, 3), None);
+    }
+}
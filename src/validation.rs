@@ -0,0 +1,373 @@
+use crate::agent::{AgentManager, Id};
+use crate::config::{Config, GameMode, OutsideRule, Space, Terrain};
+use crate::grid::{body_size, Grid, Position};
+use crate::state::{Rules, State};
+
+use std::collections::HashSet;
+use std::fmt;
+
+/// Inconsistency between the grid and the agents, or with the rules of the
+/// game mode
+#[derive(Debug)]
+pub enum Violation {
+    /// The agent's cell does not hold it
+    Missing { id: Id, position: Position },
+    /// The agent's cell holds it more than once
+    Duplicate { id: Id, position: Position },
+    /// A cell holds an agent which is somewhere else
+    Misplaced {
+        id: Id,
+        cell: Position,
+        position: Position,
+    },
+    /// A cell holds an id which belongs to no agent
+    Unknown { id: Id, cell: Position },
+    /// Two agents are each within the other's body, which collision
+    /// detection should prevent
+    TooClose { id: Id, other: Id },
+    /// An agent stands on terrain its type can not enter
+    Impassable { id: Id, terrain: Terrain },
+    /// An agent is in a state the rules never lead to
+    UnreachableState { id: Id, state: State },
+    /// The number of agents in a state differs from what the game mode
+    /// allows, or from the list of 'it' agents
+    StateCount {
+        state: State,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Missing { id, position } => {
+                write!(
+                    f,
+                    "{:?} is not in the grid at its position {:?}",
+                    id, position
+                )
+            }
+            Violation::Duplicate { id, position } => {
+                write!(
+                    f,
+                    "{:?} is in the grid more than once at {:?}",
+                    id, position
+                )
+            }
+            Violation::Misplaced { id, cell, position } => write!(
+                f,
+                "{:?} is in the grid at {:?}, but its position is {:?}",
+                id, cell, position
+            ),
+            Violation::Unknown { id, cell } => {
+                write!(
+                    f,
+                    "{:?} is in the grid at {:?}, but is not an agent",
+                    id, cell
+                )
+            }
+            Violation::TooClose { id, other } => {
+                write!(f, "{:?} and {:?} are within each other's bodies", id, other)
+            }
+            Violation::Impassable { id, terrain } => {
+                write!(f, "{:?} is on {:?}, which it can not enter", id, terrain)
+            }
+            Violation::UnreachableState { id, state } => {
+                write!(f, "{:?} is {:?}, which the rules never lead to", id, state)
+            }
+            Violation::StateCount {
+                state,
+                expected,
+                found,
+            } => write!(f, "{} agents are {:?}, expected {}", found, state, expected),
+        }
+    }
+}
+
+/// Checks the invariants of the simulation after a step, which is slow and
+/// only meant for debugging
+pub struct Validator {
+    collision_detection: bool,
+    space: Space,
+    /// States agents can be in under the rules
+    reachable: Vec<State>,
+    /// Number of 'it' agents, in game modes in which it does not change
+    it_count: Option<usize>,
+}
+
+impl Validator {
+    /// Validator for a round starting with the given agents
+    pub fn new(config: &Config, am: &AgentManager) -> Validator {
+        let rules: &Rules = &config.rules;
+        let mut reachable: Vec<State> = vec![State::Runner, State::It];
+        reachable.extend(rules.transitions.iter().map(|t| t.to));
+        // Tagging passes on being 'it' or takes runners out of the game,
+        // and spawned agents are runners, so the number of 'it' agents stays,
+        // unless agents outside the boundary are taken out of the game too
+        let eliminates_outside =
+            config.boundary.is_some() && config.outside_rule == OutsideRule::Eliminate;
+        let it_count = match config.game_mode {
+            _ if eliminates_outside => None,
+            GameMode::Classic | GameMode::Freeze | GameMode::Elimination => {
+                Some(am.get_ids_in_state(State::It).len())
+            }
+            GameMode::Team => Some(0),
+            GameMode::Infection => None,
+        };
+        Validator {
+            collision_detection: config.collision_detection,
+            space: config.space,
+            reachable,
+            it_count,
+        }
+    }
+
+    /// Every agent must be in the grid exactly once, at its position, on
+    /// terrain it can enter, and with collision detection no two agents may
+    /// be within each other's bodies. The states of agents must agree with
+    /// the rules
+    pub fn check(&self, am: &AgentManager, grid: &Grid) -> Vec<Violation> {
+        let mut violations: Vec<Violation> = vec![];
+        for (cell, ids) in grid.cells() {
+            for &id in ids {
+                if !am.contains(id) {
+                    violations.push(Violation::Unknown { id, cell });
+                } else if am.get_position(id) != cell {
+                    let position = am.get_position(id);
+                    violations.push(Violation::Misplaced { id, cell, position });
+                }
+            }
+        }
+
+        let mut too_close: HashSet<(Id, Id)> = HashSet::new();
+        for &id in am.get_ids() {
+            let position: Position = am.get_position(id);
+            let in_grid = (0..3).all(|axis| position.0[axis] < grid.shape()[axis]);
+            let count: usize = if in_grid {
+                grid.cell(position)
+                    .iter()
+                    .filter(|&&other| other == id)
+                    .count()
+            } else {
+                0
+            };
+            if count == 0 {
+                violations.push(Violation::Missing { id, position });
+            } else if count > 1 {
+                violations.push(Violation::Duplicate { id, position });
+            }
+
+            if in_grid && !am.can_enter(id, grid.terrain_at(position)) {
+                let terrain: Terrain = grid.terrain_at(position);
+                violations.push(Violation::Impassable { id, terrain });
+            }
+
+            let state: State = am.get_state(id);
+            if !self.reachable.contains(&state) {
+                violations.push(Violation::UnreachableState { id, state });
+            }
+
+            if self.collision_detection && in_grid {
+                let body_side = am.get_attributes(id).body_side;
+                let is_too_close = |other: Id| match self.space {
+                    Space::Discrete => {
+                        let other_side = am.get_attributes(other).body_side;
+                        let other_position = am.get_position(other);
+                        grid.get_subgrid_occupiers(
+                            other_position,
+                            body_size(other_side),
+                            vec![other],
+                            None,
+                        )
+                        .contains(&id)
+                    }
+                    Space::Continuous => {
+                        let other_side = am.get_attributes(other).body_side;
+                        let apart = (body_side + other_side) as f32 / 2.0;
+                        am.get_point(other).distance(am.get_point(id)) < apart
+                    }
+                };
+                // Circular bodies overlap if their centres are closer than
+                // the mean of their body sides, so the larger of two agents
+                // finds the other within its body side, give or take a cell
+                let window: usize = match self.space {
+                    Space::Discrete => body_side,
+                    Space::Continuous => 2 * body_side + 3,
+                };
+                for other in grid.get_subgrid_occupiers(position, body_size(window), vec![id], None)
+                {
+                    if !am.contains(other) || too_close.contains(&(other, id)) {
+                        continue;
+                    }
+                    if is_too_close(other) {
+                        too_close.insert((id, other));
+                        violations.push(Violation::TooClose { id, other });
+                    }
+                }
+            }
+        }
+
+        let it_found = am.get_ids_in_state(State::It).len();
+        if am.get_state_count(State::It) != it_found {
+            violations.push(Violation::StateCount {
+                state: State::It,
+                expected: it_found,
+                found: am.get_state_count(State::It),
+            });
+        }
+        if let Some(expected) = self.it_count {
+            if it_found != expected {
+                violations.push(Violation::StateCount {
+                    state: State::It,
+                    expected,
+                    found: it_found,
+                });
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{Action, ActionContext};
+    use crate::config::AgentType;
+    use crate::grid::{Point, Region};
+    use crate::state::Trigger;
+
+    /// Config of a small grid without agents of its own, for agents of body
+    /// side 3 which are placed by hand
+    fn config(game_mode: GameMode) -> Config {
+        Config {
+            game_mode,
+            rules: Rules::new(game_mode, None),
+            grid_side: 20,
+            num_agents: 0,
+            num_agents_it: 0,
+            collision_detection: true,
+            agent_types: vec![AgentType {
+                name: "small",
+                share: 1.0,
+                body_side: (3, 3),
+                tag_reach: (3, 3),
+                speed: (1, 1),
+                vision_radius: (1, 1),
+                impassable: &[],
+            }],
+            ..Config::default()
+        }
+    }
+
+    /// Agents in the given states at the given cells on row 10
+    fn agents_at(
+        config: &Config,
+        ac: &ActionContext,
+        agents: &[(State, usize)],
+    ) -> (Grid, AgentManager, Vec<Id>) {
+        let mut grid = Grid::new(config);
+        let mut am = AgentManager::new(config, ac, &mut grid);
+        let ids: Vec<Id> = agents
+            .iter()
+            .map(|&(state, x)| {
+                let position = Some(Position::new(x, 10, 0));
+                am.add_agent(state, 0, Region::WHOLE, position, &mut grid)
+                    .unwrap()
+            })
+            .collect();
+        (grid, am, ids)
+    }
+
+    #[test]
+    fn grid_must_hold_every_agent_once_at_its_position() {
+        let config = config(GameMode::Classic);
+        let ac = ActionContext::new(&config);
+        let (mut grid, am, ids) = agents_at(&config, &ac, &[(State::It, 5), (State::Runner, 9)]);
+        let validator = Validator::new(&config, &am);
+        assert!(validator.check(&am, &grid).is_empty());
+
+        let (a, b) = (ids[0], ids[1]);
+        let (position, elsewhere) = (am.get_position(a), Position::new(0, 0, 0));
+        grid.remove(position, a);
+        grid.add(elsewhere, a);
+        grid.add(am.get_position(b), b);
+        grid.add(elsewhere, Id::test(7));
+        let violations = validator.check(&am, &grid);
+        assert_eq!(violations.len(), 4, "{:?}", violations);
+        assert!(violations.iter().any(|v| matches!(
+            v,
+            Violation::Misplaced { id, cell, .. } if *id == a && *cell == elsewhere
+        )));
+        assert!(violations.iter().any(|v| matches!(
+            v,
+            Violation::Unknown { id, .. } if *id == Id::test(7)
+        )));
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, Violation::Missing { id, .. } if *id == a)));
+        assert!(violations
+            .iter()
+            .any(|v| matches!(v, Violation::Duplicate { id, .. } if *id == b)));
+    }
+
+    #[test]
+    fn bodies_must_not_overlap_with_collision_detection() {
+        // An action which moves the agent at column 5 right, whoever is
+        // there, onto the body of the agent at column 7
+        let config = config(GameMode::Classic);
+        let action = Action::movement(
+            Box::new(|id, am, _| am.get_position(id).x() == 5),
+            Box::new(|id, am, _| {
+                let position = am.get_position(id);
+                Some(Point::from(Position::new(position.x() + 1, 10, 0)))
+            }),
+        );
+        let ac = ActionContext::from_actions(vec![action], vec![1.0]);
+        let (mut grid, mut am, _) =
+            agents_at(&config, &ac, &[(State::Runner, 5), (State::Runner, 7)]);
+        let validator = Validator::new(&config, &am);
+        assert!(validator.check(&am, &grid).is_empty());
+        am.perform_actions(&grid, &ac);
+        grid.update(am.flush_log());
+        let violations = validator.check(&am, &grid);
+        assert_eq!(violations.len(), 1, "{:?}", violations);
+        assert!(matches!(violations[0], Violation::TooClose { .. }));
+    }
+
+    #[test]
+    fn states_must_agree_with_the_rules() {
+        // Tagging without passing on being 'it' makes one too many
+        let classic = config(GameMode::Classic);
+        let ac = ActionContext::new(&classic);
+        let (grid, mut am, ids) = agents_at(&classic, &ac, &[(State::It, 5), (State::Runner, 9)]);
+        let validator = Validator::new(&classic, &am);
+        am.trigger(ids[1], Trigger::Tagged);
+        let violations = validator.check(&am, &grid);
+        assert_eq!(violations.len(), 1, "{:?}", violations);
+        assert!(matches!(
+            violations[0],
+            Violation::StateCount {
+                state: State::It,
+                expected: 1,
+                found: 2
+            }
+        ));
+
+        // Agents frozen under the rules of freeze tag are in a state which
+        // classic tag never leads to
+        let freeze = config(GameMode::Freeze);
+        let (grid, mut am, ids) = agents_at(&freeze, &ac, &[(State::It, 5), (State::Runner, 9)]);
+        am.trigger(ids[1], Trigger::Tagged);
+        assert!(Validator::new(&freeze, &am).check(&am, &grid).is_empty());
+        let violations = validator.check(&am, &grid);
+        assert_eq!(violations.len(), 1, "{:?}", violations);
+        assert!(matches!(
+            violations[0],
+            Violation::UnreachableState {
+                state: State::Frozen,
+                ..
+            }
+        ));
+    }
+}